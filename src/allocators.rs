@@ -0,0 +1,733 @@
+//! Where the `Scheduler` gets its slices from.
+//!
+//! Every slice is `SLICE_SIZE_BYTES` long and aligned to `SLICE_SIZE_BYTES`, since the scheduler finds the length
+//! of a partly filled slice from the low bits of a pointer into it.
+
+use std::alloc::{alloc, dealloc, Layout};
+use std::fmt;
+use std::io;
+use std::ptr::NonNull;
+use std::sync::Mutex;
+
+use crate::numa::{self, Topology};
+use crate::scheduler::SLICE_SIZE_BYTES;
+
+const SLICE_LAYOUT: Layout = match Layout::from_size_align(SLICE_SIZE_BYTES, SLICE_SIZE_BYTES) {
+    Ok(layout) => layout,
+    Err(_) => panic!("SLICE_SIZE_BYTES should be a power of two"),
+};
+
+/// A source of slices for the `Scheduler`.
+///
+/// One allocator can be shared by the threads of `Scheduler::split_parallel`, so it takes `&self`.
+///
+/// # Safety
+///
+/// `allocate` must return memory that is `SLICE_SIZE_BYTES` long, aligned to `SLICE_SIZE_BYTES`, and not in use
+/// anywhere else until it is passed to `deallocate`.
+pub unsafe trait SliceAllocator: Sync {
+    /// A new slice, or `None` if we are out of memory.
+    fn allocate(&self) -> Option<NonNull<u8>>;
+
+    /// Give back a slice.
+    ///
+    /// # Safety
+    ///
+    /// `slice` must have come from `allocate` on this allocator, and must not be used afterwards.
+    unsafe fn deallocate(&self, slice: NonNull<u8>);
+}
+
+/// Allocates each slice from the global allocator. This is the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GlobalSlices;
+
+unsafe impl SliceAllocator for GlobalSlices {
+    fn allocate(&self) -> Option<NonNull<u8>> {
+        NonNull::new(unsafe { alloc(SLICE_LAYOUT) })
+    }
+
+    unsafe fn deallocate(&self, slice: NonNull<u8>) {
+        dealloc(slice.as_ptr(), SLICE_LAYOUT);
+    }
+}
+
+/// The slices a `Scheduler` has allocated, which it can keep from one sort to the next.
+///
+/// A scheduler borrows its input and output for as long as it lives, so to keep slices warm across sorts, keep a
+/// pool outside of them: start each scheduler with `Scheduler::with_pool`, and hand the slices back with
+/// `Scheduler::return_pool`.
+///
+/// The pool only keeps up to `limit` slices once a sort is done, and gives the rest back to its allocator.
+pub struct SlicePool<'a> {
+    allocator: &'a dyn SliceAllocator,
+    /// the addresses of our slices, since pointers are not `Send`
+    slices: Vec<usize>,
+    limit: usize,
+}
+
+impl<'a> SlicePool<'a> {
+    /// An empty pool with a limit of 0.
+    pub fn new(allocator: &'a dyn SliceAllocator) -> Self {
+        Self {
+            allocator,
+            slices: vec![],
+            limit: 0,
+        }
+    }
+
+    pub fn allocator(&self) -> &'a dyn SliceAllocator {
+        self.allocator
+    }
+
+    /// The number of slices in the pool.
+    pub fn len(&self) -> usize {
+        self.slices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slices.is_empty()
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Keep up to `max_slices` slices after each sort. Shrinks the pool if it is larger.
+    pub fn set_limit(&mut self, max_slices: usize) {
+        self.limit = max_slices;
+        self.shrink(max_slices);
+    }
+
+    /// Make sure that at least `num_slices` slices are in the pool, and raise the limit to keep them there.
+    ///
+    /// Panics if the allocator runs out.
+    pub fn reserve(&mut self, num_slices: usize) {
+        self.limit = self.limit.max(num_slices);
+        while self.slices.len() < num_slices {
+            self.allocate().expect("Could not allocate new free slice");
+        }
+    }
+
+    /// Give slices back to the allocator until at most `num_slices` are left. This does not change the limit.
+    pub fn shrink(&mut self, num_slices: usize) {
+        for addr in self.slices.drain(num_slices.min(self.slices.len())..) {
+            let slice = NonNull::new(addr as *mut u8).expect("allocated slices are not null");
+            unsafe { self.allocator.deallocate(slice) };
+        }
+    }
+
+    /// Shrink to the limit.
+    pub(crate) fn trim(&mut self) {
+        self.shrink(self.limit);
+    }
+
+    /// A new slice from the allocator, which joins the pool.
+    pub(crate) fn allocate(&mut self) -> Option<usize> {
+        let addr = self.allocator.allocate()?.as_ptr() as usize;
+        self.slices.push(addr);
+        Some(addr)
+    }
+
+    pub(crate) fn slices(&self) -> &[usize] {
+        &self.slices
+    }
+
+    /// Move the slices of `other`, which has the same allocator, into this pool. This can go over the limit.
+    pub(crate) fn append(&mut self, other: &mut SlicePool<'_>) {
+        assert!(
+            std::ptr::addr_eq(self.allocator, other.allocator),
+            "slices can only move between pools with the same allocator"
+        );
+        self.slices.append(&mut other.slices);
+    }
+}
+
+impl Drop for SlicePool<'_> {
+    fn drop(&mut self) {
+        self.shrink(0);
+    }
+}
+
+/// Hands out the slices of one region of memory, and runs out when they are all in use.
+struct Region {
+    start: usize,
+    num_slices: usize,
+    /// the slices given back, and how many slices from the start have ever been handed out
+    free: Mutex<(Vec<usize>, usize)>,
+}
+
+impl Region {
+    /// The whole slices in `len` bytes from `start`, which need not be aligned.
+    fn new(start: usize, len: usize) -> Self {
+        let aligned = start.next_multiple_of(SLICE_SIZE_BYTES);
+        let num_slices = (start + len).saturating_sub(aligned) / SLICE_SIZE_BYTES;
+        Self {
+            start: aligned,
+            num_slices,
+            free: Mutex::new((vec![], 0)),
+        }
+    }
+
+    fn allocate(&self) -> Option<NonNull<u8>> {
+        let mut free = self.free.lock().unwrap();
+        let (returned, used) = &mut *free;
+        let addr = match returned.pop() {
+            Some(addr) => addr,
+            None if *used < self.num_slices => {
+                *used += 1;
+                self.start + (*used - 1) * SLICE_SIZE_BYTES
+            }
+            None => return None,
+        };
+        NonNull::new(addr as *mut u8)
+    }
+
+    fn deallocate(&self, slice: NonNull<u8>) {
+        debug_assert!(self.contains(slice));
+        self.free.lock().unwrap().0.push(slice.as_ptr() as usize);
+    }
+
+    fn contains(&self, slice: NonNull<u8>) -> bool {
+        let addr = slice.as_ptr() as usize;
+        addr >= self.start && addr < self.start + self.num_slices * SLICE_SIZE_BYTES
+    }
+}
+
+/// Reserves a fixed number of slices from the global allocator up front, in one allocation.
+pub struct Arena {
+    region: Region,
+    layout: Layout,
+}
+
+impl Arena {
+    pub fn new(num_slices: usize) -> Self {
+        let layout =
+            Layout::from_size_align(num_slices.max(1) * SLICE_SIZE_BYTES, SLICE_SIZE_BYTES)
+                .expect("the arena should fit in the address space");
+        let ptr = unsafe { alloc(layout) };
+        if ptr.is_null() {
+            std::alloc::handle_alloc_error(layout);
+        }
+        Self {
+            region: Region::new(ptr as usize, num_slices * SLICE_SIZE_BYTES),
+            layout,
+        }
+    }
+
+    pub fn num_slices(&self) -> usize {
+        self.region.num_slices
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.region.start as *mut u8, self.layout) };
+    }
+}
+
+unsafe impl SliceAllocator for Arena {
+    fn allocate(&self) -> Option<NonNull<u8>> {
+        self.region.allocate()
+    }
+
+    unsafe fn deallocate(&self, slice: NonNull<u8>) {
+        self.region.deallocate(slice)
+    }
+}
+
+/// Carves slices out of a buffer that the caller owns, e.g. memory from its own memory manager.
+///
+/// The buffer need not be aligned, but only the whole aligned slices in it are used.
+pub struct BufferSlices<'b> {
+    region: Region,
+    _buffer: &'b mut [u8],
+}
+
+impl<'b> BufferSlices<'b> {
+    pub fn new(buffer: &'b mut [u8]) -> Self {
+        Self {
+            region: Region::new(buffer.as_mut_ptr() as usize, buffer.len()),
+            _buffer: buffer,
+        }
+    }
+
+    pub fn num_slices(&self) -> usize {
+        self.region.num_slices
+    }
+}
+
+unsafe impl SliceAllocator for BufferSlices<'_> {
+    fn allocate(&self) -> Option<NonNull<u8>> {
+        self.region.allocate()
+    }
+
+    unsafe fn deallocate(&self, slice: NonNull<u8>) {
+        self.region.deallocate(slice)
+    }
+}
+
+/// The size of a huge page on x86_64, and on aarch64 with 4 KiB base pages.
+pub const HUGE_PAGE_SIZE: usize = 2 << 20;
+
+/// What kind of pages back an `MmapArena`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pages {
+    /// Regular pages.
+    Normal,
+    /// Regular pages, which the kernel was asked to merge into huge pages with `madvise(MADV_HUGEPAGE)`. Whether it
+    /// does depends on `/sys/kernel/mm/transparent_hugepage` and on fragmentation.
+    Transparent,
+    /// Huge pages reserved with `MAP_HUGETLB`, from the pool in `/proc/sys/vm/nr_hugepages`.
+    HugeTlb,
+}
+
+impl fmt::Display for Pages {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Pages::Normal => "normal pages",
+            Pages::Transparent => "transparent huge pages",
+            Pages::HugeTlb => "hugetlb pages",
+        })
+    }
+}
+
+/// Reserves address space for a fixed number of slices with an anonymous `mmap`.
+///
+/// The kernel only backs the pages that are touched, so reserving generously is cheap. Only supported on Linux on
+/// x86_64 and aarch64.
+pub struct MmapArena {
+    region: Region,
+    mapping: (usize, usize),
+    pages: Pages,
+}
+
+impl MmapArena {
+    pub fn new(num_slices: usize) -> io::Result<Self> {
+        // one slice extra, to align the start
+        let len = (num_slices + 1) * SLICE_SIZE_BYTES;
+        let ptr = unsafe { sys::map(len, false) }?;
+        Ok(Self {
+            region: Region::new(ptr as usize, len),
+            mapping: (ptr as usize, len),
+            pages: Pages::Normal,
+        })
+    }
+
+    /// Like `new`, but on huge pages, so that scattering into many slices misses the TLB less often.
+    ///
+    /// Tries `MAP_HUGETLB` first, then transparent huge pages, and then settles for normal pages. `pages` tells
+    /// which one we got.
+    pub fn with_huge_pages(num_slices: usize) -> io::Result<Self> {
+        let len = (num_slices * SLICE_SIZE_BYTES).next_multiple_of(HUGE_PAGE_SIZE);
+        // hugetlb mappings are always aligned to the huge page size
+        if let Ok(ptr) = unsafe { sys::map(len, true) } {
+            return Ok(Self {
+                region: Region::new(ptr as usize, len),
+                mapping: (ptr as usize, len),
+                pages: Pages::HugeTlb,
+            });
+        }
+
+        // the kernel only uses huge pages for aligned 2 MiB ranges, so align the start to one
+        let len = len + HUGE_PAGE_SIZE;
+        let ptr = unsafe { sys::map(len, false) }?;
+        let start = (ptr as usize).next_multiple_of(HUGE_PAGE_SIZE);
+        let advised = unsafe { sys::advise_huge_pages(start as *mut u8, len - HUGE_PAGE_SIZE) };
+        Ok(Self {
+            region: Region::new(start, len - HUGE_PAGE_SIZE),
+            mapping: (ptr as usize, len),
+            pages: if advised.is_ok() {
+                Pages::Transparent
+            } else {
+                Pages::Normal
+            },
+        })
+    }
+
+    /// Like `new`, but with its pages preferably on NUMA node `node`, whichever thread first touches them.
+    ///
+    /// Where there is no NUMA support, this is the same as `new`.
+    pub fn on_node(num_slices: usize, node: usize) -> io::Result<Self> {
+        let arena = Self::new(num_slices)?;
+        let (start, len) = arena.mapping;
+        unsafe { numa::prefer_node(start as *mut u8, len, node) }?;
+        Ok(arena)
+    }
+
+    pub fn num_slices(&self) -> usize {
+        self.region.num_slices
+    }
+
+    pub fn pages(&self) -> Pages {
+        self.pages
+    }
+}
+
+impl Drop for MmapArena {
+    fn drop(&mut self) {
+        let (start, len) = self.mapping;
+        unsafe { sys::unmap(start as *mut u8, len) };
+    }
+}
+
+unsafe impl SliceAllocator for MmapArena {
+    fn allocate(&self) -> Option<NonNull<u8>> {
+        self.region.allocate()
+    }
+
+    unsafe fn deallocate(&self, slice: NonNull<u8>) {
+        self.region.deallocate(slice)
+    }
+}
+
+/// An `MmapArena` on each NUMA node, handing out slices from the node of the thread that asks for them.
+///
+/// With the threads of `Scheduler::split_parallel` pinned to CPUs (see `Scheduler::set_pinned_cpus`), each thread
+/// scatters into memory on its own node instead of across the interconnect. A node that runs out borrows slices
+/// from the others. On a single-node machine, this is one `MmapArena`.
+pub struct NumaSlices {
+    topology: Topology,
+    arenas: Vec<MmapArena>,
+}
+
+impl NumaSlices {
+    /// Room for `slices_per_node` slices on each node of `Topology::detect`.
+    pub fn new(slices_per_node: usize) -> io::Result<Self> {
+        Self::with_topology(Topology::detect(), slices_per_node)
+    }
+
+    pub fn with_topology(topology: Topology, slices_per_node: usize) -> io::Result<Self> {
+        let arenas = (0..topology.num_nodes())
+            .map(|node| MmapArena::on_node(slices_per_node, node))
+            .collect::<io::Result<_>>()?;
+        Ok(Self { topology, arenas })
+    }
+
+    pub fn topology(&self) -> &Topology {
+        &self.topology
+    }
+}
+
+unsafe impl SliceAllocator for NumaSlices {
+    fn allocate(&self) -> Option<NonNull<u8>> {
+        let local = self.topology.current_node();
+        let num_nodes = self.arenas.len();
+        (0..num_nodes).find_map(|ix| self.arenas[(local + ix) % num_nodes].allocate())
+    }
+
+    unsafe fn deallocate(&self, slice: NonNull<u8>) {
+        let arena = self
+            .arenas
+            .iter()
+            .find(|arena| arena.region.contains(slice))
+            .expect("the slice should have come from one of our arenas");
+        arena.deallocate(slice)
+    }
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod sys {
+    use std::io;
+    use std::os::raw::{c_int, c_long, c_void};
+
+    const PROT_READ: c_int = 1;
+    const PROT_WRITE: c_int = 2;
+    const MAP_PRIVATE: c_int = 0x02;
+    const MAP_ANONYMOUS: c_int = 0x20;
+    const MAP_NORESERVE: c_int = 0x4000;
+    const MAP_HUGETLB: c_int = 0x40000;
+    const MADV_HUGEPAGE: c_int = 14;
+
+    extern "C" {
+        fn mmap(
+            addr: *mut c_void,
+            len: usize,
+            prot: c_int,
+            flags: c_int,
+            fd: c_int,
+            offset: c_long,
+        ) -> *mut c_void;
+        fn munmap(addr: *mut c_void, len: usize) -> c_int;
+        fn madvise(addr: *mut c_void, len: usize, advice: c_int) -> c_int;
+    }
+
+    pub unsafe fn map(len: usize, hugetlb: bool) -> io::Result<*mut u8> {
+        // without a reservation, touching a hugetlb page when the pool is empty raises SIGBUS, so we reserve
+        // them up front and let the mapping fail instead
+        let flags = match hugetlb {
+            true => MAP_PRIVATE | MAP_ANONYMOUS | MAP_HUGETLB,
+            false => MAP_PRIVATE | MAP_ANONYMOUS | MAP_NORESERVE,
+        };
+        let ptr = mmap(
+            std::ptr::null_mut(),
+            len,
+            PROT_READ | PROT_WRITE,
+            flags,
+            -1,
+            0,
+        );
+        // MAP_FAILED
+        if ptr as isize == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ptr as *mut u8)
+    }
+
+    pub unsafe fn unmap(ptr: *mut u8, len: usize) {
+        munmap(ptr as *mut c_void, len);
+    }
+
+    pub unsafe fn advise_huge_pages(ptr: *mut u8, len: usize) -> io::Result<()> {
+        if madvise(ptr as *mut c_void, len, MADV_HUGEPAGE) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+mod sys {
+    use std::io;
+
+    pub unsafe fn map(_len: usize, _hugetlb: bool) -> io::Result<*mut u8> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub unsafe fn unmap(_ptr: *mut u8, _len: usize) {}
+
+    pub unsafe fn advise_huge_pages(_ptr: *mut u8, _len: usize) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lcg::LCG;
+    use crate::scheduler::{
+        BudgetExceeded, OverBudget, Scheduler, SplitStats, NUM_BUCKETS, SLICE_SIZE,
+    };
+    use crate::splitters::ScalarSplitter;
+    use crate::verify::{verify_sorted, Checksum};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Global slices, counting how many are out.
+    #[derive(Default)]
+    struct Counted {
+        live: AtomicUsize,
+    }
+
+    unsafe impl SliceAllocator for Counted {
+        fn allocate(&self) -> Option<NonNull<u8>> {
+            self.live.fetch_add(1, Ordering::Relaxed);
+            GlobalSlices.allocate()
+        }
+
+        unsafe fn deallocate(&self, slice: NonNull<u8>) {
+            self.live.fetch_sub(1, Ordering::Relaxed);
+            GlobalSlices.deallocate(slice)
+        }
+    }
+
+    impl Counted {
+        fn live(&self) -> usize {
+            self.live.load(Ordering::Relaxed)
+        }
+    }
+
+    /// Sorts `keys` with the slices of `pool`, and hands them back.
+    fn sort_with_pool(pool: &mut SlicePool<'_>, keys: &[u64]) -> SplitStats {
+        let mut input = keys.to_vec();
+        let mut output = vec![0; keys.len()];
+        let mut sched = Scheduler::with_pool(pool);
+        sched.enable_stats();
+        let stats = sched
+            .split(&mut input, &mut output, &mut ScalarSplitter::new())
+            .unwrap();
+        sched.return_pool(pool);
+        verify_sorted(&output, Checksum::of(keys)).unwrap();
+        stats
+    }
+
+    /// Takes every slice of `allocator`, checking that each is aligned and distinct.
+    fn allocate_all(allocator: &dyn SliceAllocator) -> Vec<NonNull<u8>> {
+        let slices: Vec<_> = std::iter::from_fn(|| allocator.allocate()).collect();
+        for (ix, slice) in slices.iter().enumerate() {
+            assert!(slice.as_ptr().is_aligned_to(SLICE_SIZE_BYTES));
+            assert!(!slices[..ix].contains(slice));
+        }
+        slices
+    }
+
+    #[test]
+    fn arena_runs_out() {
+        let arena = Arena::new(3);
+        assert_eq!(arena.num_slices(), 3);
+        let slices = allocate_all(&arena);
+        assert_eq!(slices.len(), 3);
+        assert!(arena.allocate().is_none());
+
+        unsafe { arena.deallocate(slices[1]) };
+        assert_eq!(arena.allocate(), Some(slices[1]));
+        assert!(arena.allocate().is_none());
+    }
+
+    #[test]
+    fn buffer_slices_run_out() {
+        let mut buffer = vec![0u8; 4 * SLICE_SIZE_BYTES];
+        // not aligned, so that only the whole slices after the first boundary are used
+        let range = buffer.as_ptr_range();
+        let buffer = &mut buffer[1..];
+        let slices = BufferSlices::new(buffer);
+        assert_eq!(slices.num_slices(), 3);
+        let taken = allocate_all(&slices);
+        assert_eq!(taken.len(), 3);
+        for slice in &taken {
+            assert!(range.contains(&(slice.as_ptr() as *const u8)));
+            assert!(
+                range.contains(&(slice.as_ptr().wrapping_add(SLICE_SIZE_BYTES - 1) as *const u8))
+            );
+        }
+
+        for &slice in &taken {
+            unsafe { slices.deallocate(slice) };
+        }
+        assert_eq!(allocate_all(&slices).len(), 3);
+    }
+
+    #[test]
+    fn exhausted_allocator_fails_the_sort() {
+        let mut random = LCG::with_seed(41);
+        let keys: Vec<u64> = (0..2 * SLICE_SIZE).map(|_| random.next()).collect();
+        // far fewer slices than the buckets of one split
+        let arena = Arena::new(8);
+
+        let mut input = keys.clone();
+        let mut output = vec![0; keys.len()];
+        let mut sched = Scheduler::new();
+        sched.set_allocator(&arena);
+        sched.set_memory_budget(usize::MAX, OverBudget::Fail);
+        let res = sched.try_split(&mut input, &mut output, &mut ScalarSplitter::new());
+        assert_eq!(
+            res.unwrap_err(),
+            BudgetExceeded {
+                budget_bytes: usize::MAX
+            }
+        );
+        drop(sched);
+
+        // or sorts what it could not split without it
+        let mut input = keys.clone();
+        let mut output = vec![0; keys.len()];
+        let mut sched = Scheduler::new();
+        sched.set_allocator(&arena);
+        sched.set_memory_budget(usize::MAX, OverBudget::SortInPlace);
+        sched.split(&mut input, &mut output, &mut ScalarSplitter::new());
+        drop(sched);
+        verify_sorted(&output, Checksum::of(&keys)).unwrap();
+    }
+
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    #[test]
+    fn huge_pages_fall_back() {
+        let arena = MmapArena::with_huge_pages(5).unwrap();
+        // a whole huge page, aligned to one, whichever pages we got
+        assert_eq!(arena.num_slices(), HUGE_PAGE_SIZE / SLICE_SIZE_BYTES);
+        assert!(arena.region.start.is_multiple_of(HUGE_PAGE_SIZE));
+        let hugetlb_pool = std::fs::read_to_string("/proc/sys/vm/nr_hugepages").unwrap_or_default();
+        if hugetlb_pool.trim() == "0" {
+            assert_ne!(arena.pages(), Pages::HugeTlb);
+        }
+
+        let slices = allocate_all(&arena);
+        assert_eq!(slices.len(), arena.num_slices());
+        for slice in slices {
+            unsafe { slice.as_ptr().write_bytes(0xA5, SLICE_SIZE_BYTES) };
+        }
+    }
+
+    #[test]
+    fn pool_reserve_limit_and_shrink() {
+        let counted = Counted::default();
+        let mut pool = SlicePool::new(&counted);
+        pool.reserve(5);
+        assert_eq!((pool.len(), pool.limit(), counted.live()), (5, 5, 5));
+        // a smaller reservation neither lowers the limit nor frees anything
+        pool.reserve(2);
+        assert_eq!((pool.len(), pool.limit()), (5, 5));
+
+        pool.set_limit(3);
+        assert_eq!((pool.len(), pool.limit(), counted.live()), (3, 3, 3));
+        pool.shrink(1);
+        assert_eq!((pool.len(), pool.limit(), counted.live()), (1, 3, 1));
+
+        let mut other = SlicePool::new(&counted);
+        other.reserve(4);
+        pool.append(&mut other);
+        assert!(other.is_empty());
+        assert_eq!(pool.len(), 5);
+        pool.trim();
+        assert_eq!((pool.len(), counted.live()), (3, 3));
+
+        drop(pool);
+        assert_eq!(counted.live(), 0);
+    }
+
+    #[test]
+    fn pool_is_reused_up_to_its_limit() {
+        let mut random = LCG::with_seed(43);
+        let keys: Vec<u64> = (0..2 * SLICE_SIZE).map(|_| random.next()).collect();
+        let counted = Counted::default();
+        let mut pool = SlicePool::new(&counted);
+        pool.set_limit(usize::MAX);
+
+        let first = sort_with_pool(&mut pool, &keys);
+        assert!(first.slices_allocated > 0);
+        assert_eq!(first.slices_reused, 0);
+        assert_eq!(pool.len(), first.slices_allocated);
+
+        // the same sort again needs nothing new
+        let second = sort_with_pool(&mut pool, &keys);
+        assert_eq!(second.slices_allocated, 0);
+        assert!(second.slices_reused >= first.slices_allocated);
+        assert_eq!(pool.len(), first.slices_allocated);
+
+        // a smaller limit frees slices now, and again once a sort is done
+        pool.set_limit(10);
+        let third = sort_with_pool(&mut pool, &keys);
+        assert_eq!(third.slices_allocated, first.slices_allocated - 10);
+        assert_eq!((pool.len(), counted.live()), (10, 10));
+    }
+
+    #[test]
+    fn base_case_slices_go_back_to_the_pool() {
+        // a key in every bucket but the last, which comes up after all of those base cases and needs splitting
+        let mut random = LCG::with_seed(47);
+        let keys: Vec<u64> = (0..3 * SLICE_SIZE)
+            .map(|ix| match ix < NUM_BUCKETS - 1 {
+                true => ((ix as u64) << 56) | random.next() >> 8,
+                false => 0xFF << 56 | random.next() >> 8,
+            })
+            .collect();
+        let counted = Counted::default();
+        let mut pool = SlicePool::new(&counted);
+        pool.set_limit(usize::MAX);
+        let stats = sort_with_pool(&mut pool, &keys);
+
+        // the last bucket is split into the slices of the base cases before it
+        assert!(
+            stats.slices_allocated < NUM_BUCKETS + 16,
+            "allocated {} slices",
+            stats.slices_allocated
+        );
+    }
+}
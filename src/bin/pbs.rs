@@ -0,0 +1,267 @@
+#[path = "../cli.rs"]
+mod cli;
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::process::exit;
+use std::time::Instant;
+
+use cli::{default_threads, option_value, parse_positive, parse_size, Size};
+use pbs::external::{ExternalSort, MIN_MEMORY_LIMIT};
+use pbs::files::{sort_file, KeyFormat};
+use pbs::verify::{Checksum, VerifyError, VerifyRuns};
+
+const USAGE: &str = "\
+Usage: pbs sort-file <INPUT> [OUTPUT] [OPTIONS]
+       pbs external-sort <INPUT> <OUTPUT> [OPTIONS]
+
+sort-file sorts a binary file of keys, into OUTPUT if given, or else in place.
+
+  --format <FORMAT>  u64, u32, i64 or f64, followed by le or be for the byte order, e.g. u64be [default: u64 in
+                     native byte order]
+  --threads <N>      threads used when sorting into OUTPUT; in place, the sort runs on one thread [default: all
+                     cores]
+
+external-sort sorts a file of native-endian u64 keys into OUTPUT, which need not fit in memory, through bucket
+files in a temporary directory.
+
+  --temp-dir <DIR>       where to put the temporary directory [default: the system's]
+  --memory-limit <SIZE>  roughly the most memory to use, e.g. 512MiB or 4GB [default: 1 GiB]
+  --threads <N>          threads used to sort each bucket in memory [default: 1]
+  --verify               read both files once more, to check that OUTPUT holds the keys of INPUT in order
+
+  -h, --help             print this message";
+
+struct SortFileArgs {
+    input: String,
+    output: Option<String>,
+    format: KeyFormat,
+    threads: usize,
+}
+
+impl SortFileArgs {
+    /// Parse the arguments after `sort-file`. Returns `None` if we were asked for help.
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Option<Self>, String> {
+        let mut paths = vec![];
+        let mut format = "u64".parse()?;
+        let mut threads = default_threads();
+        while let Some(arg) = args.next() {
+            if arg == "-h" || arg == "--help" {
+                return Ok(None);
+            }
+            if !arg.starts_with("--") {
+                paths.push(arg);
+                continue;
+            }
+            let (opt, value) = option_value(arg, &mut args)?;
+            match &opt[..] {
+                "--format" => format = value.parse()?,
+                "--threads" => threads = parse_positive(&opt, &value)?,
+                _ => return Err(format!("unknown option {opt}")),
+            }
+        }
+        let mut paths = paths.into_iter();
+        let (Some(input), output, None) = (paths.next(), paths.next(), paths.next()) else {
+            return Err("sort-file needs an input file, and at most one output file".to_string());
+        };
+        Ok(Some(Self {
+            input,
+            output,
+            format,
+            threads,
+        }))
+    }
+}
+
+struct ExternalSortArgs {
+    input: String,
+    output: String,
+    sort: ExternalSort,
+    verify: bool,
+}
+
+impl ExternalSortArgs {
+    /// Parse the arguments after `external-sort`. Returns `None` if we were asked for help.
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Option<Self>, String> {
+        let mut paths = vec![];
+        let mut sort = ExternalSort::new();
+        let mut verify = false;
+        while let Some(arg) = args.next() {
+            if arg == "-h" || arg == "--help" {
+                return Ok(None);
+            }
+            if arg == "--verify" {
+                verify = true;
+                continue;
+            }
+            if !arg.starts_with("--") {
+                paths.push(arg);
+                continue;
+            }
+            let (opt, value) = option_value(arg, &mut args)?;
+            match &opt[..] {
+                "--temp-dir" => sort.set_temp_dir(value),
+                "--memory-limit" => {
+                    let limit = parse_size(&value)?;
+                    if limit < MIN_MEMORY_LIMIT {
+                        return Err(format!(
+                            "the memory limit must be at least {}",
+                            Size(MIN_MEMORY_LIMIT)
+                        ));
+                    }
+                    sort.set_memory_limit(limit);
+                }
+                "--threads" => sort.set_threads(parse_positive(&opt, &value)?),
+                _ => return Err(format!("unknown option {opt}")),
+            }
+        }
+        let mut paths = paths.into_iter();
+        let (Some(input), Some(output), None) = (paths.next(), paths.next(), paths.next()) else {
+            return Err("external-sort needs an input and an output file".to_string());
+        };
+        Ok(Some(Self {
+            input,
+            output,
+            sort,
+            verify,
+        }))
+    }
+}
+
+/// The parsed arguments, or exit after printing the usage if there are none.
+fn args_or_exit<A>(args: Result<Option<A>, String>) -> A {
+    match args {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            println!("{USAGE}");
+            exit(0);
+        }
+        Err(err) => {
+            eprintln!("error: {err}\n\n{USAGE}");
+            exit(2);
+        }
+    }
+}
+
+fn main() {
+    let mut cli_args = std::env::args().skip(1);
+    match cli_args.next().as_deref() {
+        Some("sort-file") => sort_file_command(args_or_exit(SortFileArgs::parse(cli_args))),
+        Some("external-sort") => {
+            external_sort_command(args_or_exit(ExternalSortArgs::parse(cli_args)))
+        }
+        Some("-h" | "--help") => println!("{USAGE}"),
+        Some(command) => {
+            eprintln!("error: unknown command {command}\n\n{USAGE}");
+            exit(2);
+        }
+        None => {
+            eprintln!("{USAGE}");
+            exit(2);
+        }
+    }
+}
+
+fn sort_file_command(args: SortFileArgs) {
+    let start = Instant::now();
+    let output = args.output.as_deref().map(Path::new);
+    if let Err(err) = sort_file(Path::new(&args.input), output, args.format, args.threads) {
+        eprintln!("error: could not sort {}: {err}", args.input);
+        exit(1);
+    }
+    let len =
+        std::fs::metadata(output.unwrap_or(Path::new(&args.input))).map_or(0, |meta| meta.len());
+    let num_keys = len / args.format.key_type.width() as u64;
+    eprintln!(
+        "sorted {num_keys} {} keys in {:.2} s",
+        args.format,
+        start.elapsed().as_secs_f64()
+    );
+}
+
+fn external_sort_command(args: ExternalSortArgs) {
+    let fail = |path: &str, err: std::io::Error| -> ! {
+        eprintln!("error: {path}: {err}");
+        exit(1);
+    };
+    let (input, output) = (Path::new(&args.input), Path::new(&args.output));
+    let checksum = args
+        .verify
+        .then(|| file_checksum(input).unwrap_or_else(|err| fail(&args.input, err)));
+    let start = Instant::now();
+    let stats = args
+        .sort
+        .sort_file(input, output)
+        .unwrap_or_else(|err| fail(&args.input, err));
+    let secs = start.elapsed().as_secs_f64();
+    if let Some(checksum) = checksum {
+        let verified =
+            verify_sorted_file(output, checksum).unwrap_or_else(|err| fail(&args.output, err));
+        if let Err(err) = verified {
+            eprintln!("error: {} is not sorted correctly: {err}", args.output);
+            exit(1);
+        }
+    }
+
+    eprintln!(
+        "split: {:.2} s, {} bucket files, {} written, up to {} levels",
+        stats.split_time.as_secs_f64(),
+        stats.bucket_files,
+        Size(stats.temp_bytes),
+        stats.levels,
+    );
+    eprintln!(
+        "sort: {:.2} s, {} buckets sorted in memory",
+        stats.sort_time.as_secs_f64(),
+        stats.buckets_sorted,
+    );
+    eprintln!(
+        "sorted {} keys ({}) in {secs:.2} s",
+        stats.records,
+        Size(stats.records * size_of::<u64>())
+    );
+}
+
+/// The checksum of the native-endian u64 keys in `path`, read a chunk at a time.
+fn file_checksum(path: &Path) -> std::io::Result<Checksum> {
+    let mut checksum = Checksum::default();
+    for_each_key_chunk(path, |keys| {
+        keys.iter().for_each(|key| checksum.add(key));
+    })?;
+    Ok(checksum)
+}
+
+/// Like `verify_sorted`, for the native-endian u64 keys in `path`, which need not fit in memory.
+fn verify_sorted_file(path: &Path, expected: Checksum) -> std::io::Result<Result<(), VerifyError>> {
+    let mut verify = VerifyRuns::<u64>::new();
+    for_each_key_chunk(path, |keys| verify.add(keys))?;
+    Ok(verify.finish(expected))
+}
+
+fn for_each_key_chunk(path: &Path, mut f: impl FnMut(&[u64])) -> std::io::Result<()> {
+    let mut file = BufReader::with_capacity(1 << 20, File::open(path)?);
+    let mut keys = vec![];
+    loop {
+        let buf = file.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let whole = buf.len() - buf.len() % size_of::<u64>();
+        if whole == 0 {
+            // the buffer ends part way through a key
+            let mut key = [0; size_of::<u64>()];
+            file.read_exact(&mut key)?;
+            f(&[u64::from_ne_bytes(key)]);
+            continue;
+        }
+        keys.clear();
+        keys.extend(
+            buf[..whole]
+                .chunks_exact(size_of::<u64>())
+                .map(|bytes| u64::from_ne_bytes(bytes.try_into().unwrap())),
+        );
+        f(&keys);
+        file.consume(whole);
+    }
+}
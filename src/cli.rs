@@ -0,0 +1,74 @@
+//! Command line parsing shared by `pbs-bench` and `pbs`.
+
+use std::fmt;
+
+/// The default for `--threads`: all cores.
+pub fn default_threads() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// Split the option `arg` into its name and value, accepting both `--opt value` and `--opt=value`. In the first
+/// form, the value is taken from `args`.
+pub fn option_value(
+    arg: String,
+    args: &mut impl Iterator<Item = String>,
+) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((opt, value)) => Ok((opt.to_string(), value.to_string())),
+        None => {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("missing value for {arg}"))?;
+            Ok((arg, value))
+        }
+    }
+}
+
+pub fn parse_positive(opt: &str, value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(0) | Err(_) => Err(format!("{opt} must be a positive integer, got {value:?}")),
+        Ok(n) => Ok(n),
+    }
+}
+
+/// Parse a number of bytes with an optional binary (KiB, MiB, ...) or decimal (KB, MB, ...) suffix.
+pub fn parse_size(s: &str) -> Result<usize, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, suffix) = s.split_at(split);
+    let multiplier: usize = match &suffix.to_ascii_lowercase()[..] {
+        "" | "b" => 1,
+        "k" | "kib" => 1 << 10,
+        "m" | "mib" => 1 << 20,
+        "g" | "gib" => 1 << 30,
+        "t" | "tib" => 1 << 40,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        "tb" => 1_000_000_000_000,
+        _ => return Err(format!("unknown size suffix in {s:?}")),
+    };
+    num.parse::<usize>()
+        .ok()
+        .and_then(|num| num.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid size {s:?}"))
+}
+
+/// A number of bytes, displayed with a binary suffix.
+pub struct Size(pub usize);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let suffixes = ["B", "KiB", "MiB", "GiB", "TiB"];
+        let mut size = self.0 as f64;
+        let mut suffix = 0;
+        while size >= 1024.0 && suffix + 1 < suffixes.len() {
+            size /= 1024.0;
+            suffix += 1;
+        }
+        if size.fract() == 0.0 {
+            write!(f, "{size} {}", suffixes[suffix])
+        } else {
+            write!(f, "{size:.2} {}", suffixes[suffix])
+        }
+    }
+}
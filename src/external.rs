@@ -0,0 +1,453 @@
+//! Sorting files of `u64` keys that are larger than memory.
+//!
+//! `ExternalSort` splits the file on the first byte of each key into a bucket file per byte. Like `ActiveSlices`, it
+//! keeps one slice per bucket in memory and writes it to the bucket's file once it is full. Buckets that fit in
+//! memory are then sorted with the `Scheduler` and appended to the output in order. Buckets that do not fit are
+//! split again on their next byte.
+//!
+//! Keys are stored in native byte order, 8 bytes each.
+
+use std::alloc::{alloc, dealloc, handle_alloc_error, Layout};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::scheduler::{OverBudget, Scheduler, NUM_BUCKETS, SLICE_SIZE, SLICE_SIZE_BYTES};
+use crate::splitters::ScalarSplitter;
+
+/// The least memory an external sort can work in: a slice for each bucket while splitting, and as much again to
+/// read the input into, with room to spare for sorting buckets in memory.
+pub const MIN_MEMORY_LIMIT: usize = 4 * NUM_BUCKETS * SLICE_SIZE_BYTES;
+
+// the records read from the input at a time while splitting
+const READ_LEN: usize = NUM_BUCKETS * SLICE_SIZE;
+
+/// Sorts a file of `u64` keys into another, in bounded memory, using temporary files for the buckets.
+#[derive(Clone, Debug)]
+pub struct ExternalSort {
+    temp_dir: PathBuf,
+    memory_limit: usize,
+    threads: usize,
+}
+
+/// What an external sort did, returned by `ExternalSort::sort_file`.
+#[derive(Clone, Debug, Default)]
+pub struct ExternalStats {
+    pub records: usize,
+    /// The bucket files written, at every level.
+    pub bucket_files: usize,
+    /// The bytes written to bucket files, at every level.
+    pub temp_bytes: usize,
+    /// The most levels a key was split on before its bucket fit in memory.
+    pub levels: usize,
+    /// Buckets sorted in memory with the `Scheduler`.
+    pub buckets_sorted: usize,
+    /// Time spent splitting the input and bucket files.
+    pub split_time: Duration,
+    /// Time spent sorting buckets in memory and writing them out.
+    pub sort_time: Duration,
+}
+
+impl Default for ExternalSort {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ExternalSort {
+    /// An external sort in `std::env::temp_dir`, within 1 GiB of memory, on one thread.
+    pub fn new() -> Self {
+        Self {
+            temp_dir: std::env::temp_dir(),
+            memory_limit: 1 << 30,
+            threads: 1,
+        }
+    }
+
+    /// Write bucket files to a new directory inside `dir`, which is removed once the sort is done.
+    ///
+    /// The bucket files take up to about the size of the input, on top of the output.
+    pub fn set_temp_dir(&mut self, dir: impl Into<PathBuf>) {
+        self.temp_dir = dir.into();
+    }
+
+    /// Use at most about `bytes` of memory for buffers, buckets, and the slices of the `Scheduler`.
+    ///
+    /// Panics if `bytes` is less than `MIN_MEMORY_LIMIT`.
+    pub fn set_memory_limit(&mut self, bytes: usize) {
+        assert!(
+            bytes >= MIN_MEMORY_LIMIT,
+            "an external sort needs a memory limit of at least {MIN_MEMORY_LIMIT} bytes"
+        );
+        self.memory_limit = bytes;
+    }
+
+    /// Sort each bucket on `threads` threads, with `Scheduler::split_parallel`.
+    pub fn set_threads(&mut self, threads: usize) {
+        assert!(threads > 0);
+        self.threads = threads;
+    }
+
+    /// Sort the keys of `input` into `output`, which is created or truncated.
+    pub fn sort_file(&self, input: &Path, output: &Path) -> io::Result<ExternalStats> {
+        let input = File::open(input)?;
+        let len = input.metadata()?.len() as usize;
+        if !len.is_multiple_of(size_of::<u64>()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the input is not a whole number of u64 keys",
+            ));
+        }
+
+        let work_dir = WorkDir::create(&self.temp_dir)?;
+        let mut output = BufWriter::with_capacity(SLICE_SIZE_BYTES, File::create(output)?);
+        let mut stats = ExternalStats {
+            records: len / size_of::<u64>(),
+            ..ExternalStats::default()
+        };
+        let bucket = BucketFile {
+            file: input,
+            len: len / size_of::<u64>(),
+            level: 0,
+            name: String::new(),
+        };
+        self.sort_bucket(bucket, &work_dir.0, &mut output, &mut stats)?;
+        output.flush()?;
+        Ok(stats)
+    }
+
+    /// Append the keys of `bucket`, which all share its first `level` bytes, to `output` in order.
+    fn sort_bucket(
+        &self,
+        bucket: BucketFile,
+        work_dir: &Path,
+        output: &mut impl Write,
+        stats: &mut ExternalStats,
+    ) -> io::Result<()> {
+        stats.levels = stats.levels.max(bucket.level);
+        if bucket.level == size_of::<u64>() {
+            // split on every byte, so every key is the same
+            io::copy(&mut { bucket.file }, output)?;
+            return Ok(());
+        }
+        if self.fits_in_memory(bucket.len) {
+            let start = Instant::now();
+            self.sort_in_memory(bucket, output)?;
+            stats.buckets_sorted += 1;
+            stats.sort_time += start.elapsed();
+            return Ok(());
+        }
+
+        let start = Instant::now();
+        let level = bucket.level + 1;
+        let children = split_file(bucket, work_dir, stats)?;
+        stats.split_time += start.elapsed();
+        for (name, len) in children.into_iter().flatten() {
+            let path = work_dir.join(format!("{name}.bucket"));
+            let file = File::open(&path)?;
+            // the file stays readable until we close it, and we only need it once
+            fs::remove_file(&path)?;
+            let child = BucketFile {
+                file,
+                len,
+                level,
+                name,
+            };
+            self.sort_bucket(child, work_dir, output, stats)?;
+        }
+        Ok(())
+    }
+
+    /// Whether we can sort `len` keys in memory: the input and output of `Scheduler::split`, and the slices it
+    /// reserves to split a bucket, at two levels at once. The input's own slices are reused once they are split.
+    fn fits_in_memory(&self, len: usize) -> bool {
+        let padded_bytes = len.next_multiple_of(SLICE_SIZE) * size_of::<u64>();
+        2 * padded_bytes + 2 * (NUM_BUCKETS + 1) * SLICE_SIZE_BYTES <= self.memory_limit
+    }
+
+    fn sort_in_memory(&self, bucket: BucketFile, output: &mut impl Write) -> io::Result<()> {
+        if bucket.len == 0 {
+            return Ok(());
+        }
+        // `split` needs a whole number of slices, so pad with keys that sort last
+        let mut input = AlignedKeys::new(bucket.len.next_multiple_of(SLICE_SIZE));
+        { bucket.file }.read_exact(as_bytes_mut(&mut input[..bucket.len]))?;
+        let mut sorted = AlignedKeys::new(input.len());
+
+        {
+            let mut sched = Scheduler::new();
+            // whatever the buffers leave of the limit, see `fits_in_memory`
+            let buffer_bytes = (input.len() + sorted.len()) * size_of::<u64>();
+            sched.set_memory_budget(self.memory_limit - buffer_bytes, OverBudget::SortInPlace);
+            let splitter = ScalarSplitter::new();
+            if self.threads > 1 {
+                sched.split_parallel(&mut input, &mut sorted, &splitter, self.threads);
+            } else {
+                sched.split(&mut input, &mut sorted, &mut splitter.clone());
+            }
+        }
+
+        output.write_all(as_bytes(&sorted[..bucket.len]))
+    }
+}
+
+/// A file of keys that share their first `level` bytes, which `name` spells out in hex.
+struct BucketFile {
+    file: File,
+    len: usize,
+    level: usize,
+    name: String,
+}
+
+/// Split `bucket` on byte `bucket.level` of each key, into a bucket file in `work_dir` for each byte that occurs.
+///
+/// Returns the name and length of each child. Their files are closed, so that we only ever have the files of one
+/// split open, however deep we go.
+fn split_file(
+    mut bucket: BucketFile,
+    work_dir: &Path,
+    stats: &mut ExternalStats,
+) -> io::Result<Vec<Option<(String, usize)>>> {
+    let shift = (size_of::<u64>() - 1 - bucket.level) * 8;
+    let mut children = (0..NUM_BUCKETS)
+        .map(|_| None)
+        .collect::<Vec<Option<(File, usize)>>>();
+    // one slice for each child, written out whenever it fills up
+    let mut active = (0..NUM_BUCKETS)
+        .map(|_| Vec::with_capacity(SLICE_SIZE))
+        .collect::<Vec<_>>();
+
+    let name = |ix: usize| format!("{}{ix:02x}", bucket.name);
+    let mut flush = |ix: usize, keys: &mut Vec<u64>, stats: &mut ExternalStats| -> io::Result<()> {
+        let (file, len) = match &mut children[ix] {
+            Some(child) => child,
+            none => {
+                let path = work_dir.join(format!("{}.bucket", name(ix)));
+                stats.bucket_files += 1;
+                none.insert((File::create_new(path)?, 0))
+            }
+        };
+        file.write_all(as_bytes(keys))?;
+        *len += keys.len();
+        stats.temp_bytes += size_of_val(&keys[..]);
+        keys.clear();
+        Ok(())
+    };
+
+    let mut keys = vec![0; READ_LEN];
+    let mut left = bucket.len;
+    while left > 0 {
+        let chunk = &mut keys[..left.min(READ_LEN)];
+        bucket.file.read_exact(as_bytes_mut(chunk))?;
+        left -= chunk.len();
+        for &key in chunk.iter() {
+            let ix = (key >> shift) as usize & (NUM_BUCKETS - 1);
+            let slice = &mut active[ix];
+            slice.push(key);
+            if slice.len() == SLICE_SIZE {
+                flush(ix, slice, stats)?;
+            }
+        }
+    }
+    for (ix, slice) in active.iter_mut().enumerate() {
+        if !slice.is_empty() {
+            flush(ix, slice, stats)?;
+        }
+    }
+
+    Ok(children
+        .into_iter()
+        .enumerate()
+        .map(|(ix, child)| child.map(|(_, len)| (name(ix), len)))
+        .collect())
+}
+
+/// Keys in memory that start on a slice boundary, so that `Scheduler::split` can reuse their slices as it goes.
+struct AlignedKeys {
+    ptr: *mut u64,
+    len: usize,
+}
+
+impl AlignedKeys {
+    /// `len` keys, all `u64::MAX`.
+    fn new(len: usize) -> Self {
+        let layout = Self::layout(len);
+        let ptr = unsafe { alloc(layout) } as *mut u64;
+        if ptr.is_null() {
+            handle_alloc_error(layout);
+        }
+        // every bit set is `u64::MAX`
+        unsafe { ptr.write_bytes(0xFF, len) };
+        Self { ptr, len }
+    }
+
+    fn layout(len: usize) -> Layout {
+        Layout::from_size_align(len.max(1) * size_of::<u64>(), SLICE_SIZE_BYTES)
+            .expect("the keys should fit in the address space")
+    }
+}
+
+impl Deref for AlignedKeys {
+    type Target = [u64];
+
+    fn deref(&self) -> &[u64] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl DerefMut for AlignedKeys {
+    fn deref_mut(&mut self) -> &mut [u64] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl Drop for AlignedKeys {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr as *mut u8, Self::layout(self.len)) };
+    }
+}
+
+/// A directory of our own for bucket files, removed when dropped.
+struct WorkDir(PathBuf);
+
+impl WorkDir {
+    fn create(parent: &Path) -> io::Result<Self> {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let path = parent.join(format!("pbs-external-{}-{id}", std::process::id()));
+        fs::create_dir(&path)?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for WorkDir {
+    fn drop(&mut self) {
+        // this also removes the bucket files of a sort that failed part way
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn as_bytes(keys: &[u64]) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(keys.as_ptr() as *const u8, size_of_val(keys)) }
+}
+
+fn as_bytes_mut(keys: &mut [u64]) -> &mut [u8] {
+    unsafe { std::slice::from_raw_parts_mut(keys.as_mut_ptr() as *mut u8, size_of_val(keys)) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lcg::LCG;
+    use crate::verify::{verify_sorted, Checksum};
+
+    /// A directory of its own for each test, removed when dropped.
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("pbs-external-test-{}-{name}", std::process::id()));
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        /// A directory for the bucket files, which should be empty again after every sort.
+        fn temp(&self) -> PathBuf {
+            let path = self.0.join("temp");
+            fs::create_dir_all(&path).unwrap();
+            path
+        }
+
+        fn assert_no_work_dir(&self) {
+            assert_eq!(fs::read_dir(self.temp()).unwrap().count(), 0);
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn read_keys(path: &Path) -> Vec<u64> {
+        let bytes = fs::read(path).unwrap();
+        bytes
+            .chunks_exact(size_of::<u64>())
+            .map(|key| u64::from_ne_bytes(key.try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn sorts_over_several_levels() {
+        let dir = TestDir::new("levels");
+        let mut sort = ExternalSort::new();
+        sort.set_temp_dir(dir.temp());
+        sort.set_memory_limit(MIN_MEMORY_LIMIT);
+
+        // one key repeated more than fits in memory, which is split on every byte, and a few others around it that
+        // end up in small buckets at the first and the last levels
+        let common = 0x4200_1122_3344_5566;
+        let num_common = 2_200_000;
+        assert!(!sort.fits_in_memory(num_common));
+        let mut random = LCG::with_seed(47);
+        let mut keys = vec![common; num_common];
+        for ix in 0..3000 {
+            let other = match ix % 3 {
+                0 => (0x10 + ix as u64 % 3) << 56 | random.next() >> 8,
+                1 => common ^ (1 + ix as u64 % 4) << 8,
+                _ => common ^ (1 + ix as u64 % 255),
+            };
+            keys.insert(random.next() as usize % keys.len(), other);
+        }
+        let input = dir.0.join("input");
+        fs::write(&input, as_bytes(&keys)).unwrap();
+
+        let output = dir.0.join("output");
+        let stats = sort.sort_file(&input, &output).unwrap();
+        assert_eq!(stats.records, keys.len());
+        assert_eq!(stats.levels, size_of::<u64>());
+        // the common key was written to a bucket file at every level
+        assert!(stats.bucket_files > size_of::<u64>());
+        assert!(stats.temp_bytes >= size_of::<u64>() * size_of_val(&keys[..num_common]));
+        assert!(stats.buckets_sorted > 1);
+        dir.assert_no_work_dir();
+
+        let sorted = read_keys(&output);
+        verify_sorted(&sorted, Checksum::of(&keys)).unwrap();
+        // the input is untouched
+        assert_eq!(read_keys(&input), keys);
+    }
+
+    #[test]
+    fn sorts_empty_file() {
+        let dir = TestDir::new("empty");
+        let mut sort = ExternalSort::new();
+        sort.set_temp_dir(dir.temp());
+        let input = dir.0.join("input");
+        fs::write(&input, []).unwrap();
+        let output = dir.0.join("output");
+        let stats = sort.sort_file(&input, &output).unwrap();
+        assert_eq!(stats.records, 0);
+        assert_eq!(stats.bucket_files, 0);
+        assert_eq!(fs::read(&output).unwrap(), []);
+        dir.assert_no_work_dir();
+    }
+
+    #[test]
+    fn rejects_partial_keys() {
+        let dir = TestDir::new("partial");
+        let mut sort = ExternalSort::new();
+        sort.set_temp_dir(dir.temp());
+        let input = dir.0.join("input");
+        fs::write(&input, [7; 8 * 3 + 5]).unwrap();
+        let output = dir.0.join("output");
+        let err = sort.sort_file(&input, &output).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(!output.exists());
+        dir.assert_no_work_dir();
+    }
+}
@@ -0,0 +1,496 @@
+//! Sorting binary files of keys through a memory mapping, see `sort_file`.
+//!
+//! Only supported on Linux on x86_64 and aarch64.
+
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::records::Record;
+use crate::scheduler::{slice_len, Scheduler, SLICE_SIZE_BYTES};
+use crate::splitters::ScalarSplitter;
+use crate::transforms::{FlipSign, FloatOrder, Identity, KeyTransform};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyType {
+    U64,
+    U32,
+    I64,
+    F64,
+}
+
+impl KeyType {
+    /// The bytes of one key.
+    pub fn width(self) -> usize {
+        match self {
+            KeyType::U32 => 4,
+            KeyType::U64 | KeyType::I64 | KeyType::F64 => 8,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ByteOrder {
+    Little,
+    Big,
+}
+
+impl ByteOrder {
+    pub const NATIVE: ByteOrder = if cfg!(target_endian = "little") {
+        ByteOrder::Little
+    } else {
+        ByteOrder::Big
+    };
+}
+
+/// How the keys of a file are stored, e.g. "u64le" or "f64be". Without a byte order, as in "u32", it is native.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyFormat {
+    pub key_type: KeyType,
+    pub byte_order: ByteOrder,
+}
+
+impl FromStr for KeyFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key_type, byte_order) = match s.strip_suffix("le") {
+            Some(key_type) => (key_type, ByteOrder::Little),
+            None => match s.strip_suffix("be") {
+                Some(key_type) => (key_type, ByteOrder::Big),
+                None => (s, ByteOrder::NATIVE),
+            },
+        };
+        let key_type = match key_type {
+            "u64" => KeyType::U64,
+            "u32" => KeyType::U32,
+            "i64" => KeyType::I64,
+            "f64" => KeyType::F64,
+            _ => {
+                return Err(format!(
+                    "unknown key format {s:?}, expected u64, u32, i64 or f64, optionally followed by le or be"
+                ))
+            }
+        };
+        Ok(Self {
+            key_type,
+            byte_order,
+        })
+    }
+}
+
+impl fmt::Display for KeyFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let key_type = match self.key_type {
+            KeyType::U64 => "u64",
+            KeyType::U32 => "u32",
+            KeyType::I64 => "i64",
+            KeyType::F64 => "f64",
+        };
+        let byte_order = match self.byte_order {
+            ByteOrder::Little => "le",
+            ByteOrder::Big => "be",
+        };
+        write!(f, "{key_type}{byte_order}")
+    }
+}
+
+/// Sort the keys in the file `input`, into the file `output` (which is created or truncated), or in place.
+///
+/// Both files are memory mapped. In place, the sort runs on one thread with `Scheduler::split_in_place`, and writes
+/// back to the file's own pages, so the file need not fit in memory. Into `output`, it runs on `threads` threads and
+/// the input file is left as it was, but the sort uses the input's slices as scratch space: each page of the input
+/// is copied into memory when it is first written, so this needs about the size of the input in memory, besides
+/// the pages of `output`.
+pub fn sort_file(
+    input: &Path,
+    output: Option<&Path>,
+    format: KeyFormat,
+    threads: usize,
+) -> io::Result<()> {
+    assert!(threads > 0);
+    let in_place = output.is_none();
+    let input = File::options().read(true).write(in_place).open(input)?;
+    let len = input.metadata()?.len() as usize;
+    if !len.is_multiple_of(format.key_type.width()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("the file is not a whole number of {} keys", format),
+        ));
+    }
+    let output = match output {
+        Some(path) => {
+            // a shared mapping needs to read the file too
+            let file = File::options()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)?;
+            file.set_len(len as u64)?;
+            Some(file)
+        }
+        None => None,
+    };
+    if len == 0 {
+        return Ok(());
+    }
+
+    // we only write the input back in place, since `split` also uses the input's slices as scratch space
+    let mut input = Mapping::new(&input, len, in_place)?;
+    let mut output = output
+        .map(|file| Mapping::new(&file, len, true))
+        .transpose()?;
+    let output = output.as_mut();
+
+    let swapped = format.byte_order != ByteOrder::NATIVE;
+    match (format.key_type, swapped) {
+        (KeyType::U64, false) => sort_mapped::<u64, _>(&mut input, output, Identity, threads),
+        (KeyType::U64, true) => {
+            sort_mapped::<u64, _>(&mut input, output, |key: u64| key.swap_bytes(), threads)
+        }
+        (KeyType::I64, false) => sort_mapped::<u64, _>(&mut input, output, FlipSign, threads),
+        (KeyType::I64, true) => sort_mapped::<u64, _>(
+            &mut input,
+            output,
+            |key: u64| FlipSign.transform(key.swap_bytes()),
+            threads,
+        ),
+        (KeyType::F64, false) => sort_mapped::<u64, _>(&mut input, output, FloatOrder, threads),
+        (KeyType::F64, true) => sort_mapped::<u64, _>(
+            &mut input,
+            output,
+            |key: u64| FloatOrder.transform(key.swap_bytes()),
+            threads,
+        ),
+        (KeyType::U32, false) => sort_mapped::<u32, _>(&mut input, output, Identity, threads),
+        // a u32 key is in the top half of the word, see `Record for u32`
+        (KeyType::U32, true) => sort_mapped::<u32, _>(
+            &mut input,
+            output,
+            |key: u64| key.swap_bytes() << 32,
+            threads,
+        ),
+    }
+    Ok(())
+}
+
+fn sort_mapped<R, T>(
+    input: &mut Mapping,
+    output: Option<&mut Mapping>,
+    transform: T,
+    threads: usize,
+) where
+    R: Record + Send,
+    T: KeyTransform + Clone + Send,
+{
+    let records = unsafe { input.as_mut_slice::<R>() };
+    let mut output = output.map(|output| unsafe { output.as_mut_slice::<R>() });
+
+    // the scheduler sorts whole slices, so the last few records are sorted on their own and merged in after
+    let len = records.len();
+    let whole_len = len - len % slice_len::<R>();
+    let key = |record: &R| transform.transform(record.key());
+    let mut rest = records[whole_len..].to_vec();
+    rest.sort_unstable_by_key(key);
+
+    {
+        let whole = &mut records[..whole_len];
+        let splitter = ScalarSplitter::with_transform(transform.clone());
+        let mut sched = Scheduler::new();
+        match &mut output {
+            None => {
+                sched.split_in_place(whole, &mut splitter.clone());
+            }
+            Some(output) => {
+                let output = &mut output[..whole_len];
+                if threads > 1 {
+                    sched.split_parallel(whole, output, &splitter, threads);
+                } else {
+                    sched.split(whole, output, &mut splitter.clone());
+                }
+            }
+        }
+    }
+
+    merge_rest(output.unwrap_or(records), whole_len, &rest, key);
+}
+
+/// Merge the sorted `rest` into the end of `dest`, whose first `sorted_len` records are sorted.
+///
+/// This goes from the back, so that every record of `dest` is moved before the space it was in is needed.
+fn merge_rest<R: Copy>(dest: &mut [R], sorted_len: usize, rest: &[R], key: impl Fn(&R) -> u64) {
+    debug_assert_eq!(dest.len(), sorted_len + rest.len());
+    let (mut sorted_ix, mut rest_ix) = (sorted_len, rest.len());
+    while rest_ix > 0 {
+        let dest_ix = sorted_ix + rest_ix - 1;
+        if sorted_ix > 0 && key(&dest[sorted_ix - 1]) > key(&rest[rest_ix - 1]) {
+            dest[dest_ix] = dest[sorted_ix - 1];
+            sorted_ix -= 1;
+        } else {
+            dest[dest_ix] = rest[rest_ix - 1];
+            rest_ix -= 1;
+        }
+    }
+}
+
+/// A file mapped into memory, starting on a slice boundary.
+struct Mapping {
+    start: *mut u8,
+    len: usize,
+    /// the address space we reserved to align the mapping, which unmapping also unmaps the file from
+    reserved: (*mut u8, usize),
+}
+
+impl Mapping {
+    /// Map the first `len` bytes of `file`. Writes go back to the file if `shared`, and are private otherwise.
+    fn new(file: &File, len: usize, shared: bool) -> io::Result<Self> {
+        let reserved_len = len + SLICE_SIZE_BYTES;
+        let reserved = unsafe { sys::reserve(reserved_len) }?;
+        let start = (reserved as usize).next_multiple_of(SLICE_SIZE_BYTES) as *mut u8;
+        if let Err(err) = unsafe { sys::map_file_at(start, len, file, shared) } {
+            unsafe { sys::unmap(reserved, reserved_len) };
+            return Err(err);
+        }
+        Ok(Self {
+            start,
+            len,
+            reserved: (reserved, reserved_len),
+        })
+    }
+
+    /// # Safety
+    ///
+    /// Any bit pattern must be a valid `R`, and the file must not change under us.
+    unsafe fn as_mut_slice<R>(&mut self) -> &mut [R] {
+        std::slice::from_raw_parts_mut(self.start as *mut R, self.len / size_of::<R>())
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        let (ptr, len) = self.reserved;
+        unsafe { sys::unmap(ptr, len) };
+    }
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod sys {
+    use std::fs::File;
+    use std::io;
+    use std::os::fd::AsRawFd;
+    use std::os::raw::{c_int, c_long, c_void};
+
+    const PROT_READ: c_int = 1;
+    const PROT_WRITE: c_int = 2;
+    const MAP_SHARED: c_int = 0x01;
+    const MAP_PRIVATE: c_int = 0x02;
+    const MAP_FIXED: c_int = 0x10;
+    const MAP_ANONYMOUS: c_int = 0x20;
+    const MAP_NORESERVE: c_int = 0x4000;
+
+    extern "C" {
+        fn mmap(
+            addr: *mut c_void,
+            len: usize,
+            prot: c_int,
+            flags: c_int,
+            fd: c_int,
+            offset: c_long,
+        ) -> *mut c_void;
+        fn munmap(addr: *mut c_void, len: usize) -> c_int;
+    }
+
+    unsafe fn map(addr: *mut u8, len: usize, flags: c_int, fd: c_int) -> io::Result<*mut u8> {
+        let ptr = mmap(
+            addr as *mut c_void,
+            len,
+            PROT_READ | PROT_WRITE,
+            flags,
+            fd,
+            0,
+        );
+        // MAP_FAILED
+        if ptr as isize == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ptr as *mut u8)
+    }
+
+    pub unsafe fn reserve(len: usize) -> io::Result<*mut u8> {
+        map(
+            std::ptr::null_mut(),
+            len,
+            MAP_PRIVATE | MAP_ANONYMOUS | MAP_NORESERVE,
+            -1,
+        )
+    }
+
+    pub unsafe fn map_file_at(
+        addr: *mut u8,
+        len: usize,
+        file: &File,
+        shared: bool,
+    ) -> io::Result<()> {
+        let sharing = if shared { MAP_SHARED } else { MAP_PRIVATE };
+        map(addr, len, sharing | MAP_FIXED, file.as_raw_fd())?;
+        Ok(())
+    }
+
+    pub unsafe fn unmap(ptr: *mut u8, len: usize) {
+        munmap(ptr as *mut c_void, len);
+    }
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+mod sys {
+    use std::fs::File;
+    use std::io;
+
+    pub unsafe fn reserve(_len: usize) -> io::Result<*mut u8> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub unsafe fn map_file_at(
+        _addr: *mut u8,
+        _len: usize,
+        _file: &File,
+        _shared: bool,
+    ) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub unsafe fn unmap(_ptr: *mut u8, _len: usize) {}
+}
+
+#[cfg(all(
+    test,
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::lcg::LCG;
+
+    /// A file in the temp directory, removed when dropped.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, contents: &[u8]) -> Self {
+            let path = std::env::temp_dir().join(format!("pbs-test-{}-{name}", std::process::id()));
+            std::fs::write(&path, contents).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn random_keys(len: usize, shift: u32) -> Vec<u64> {
+        let mut random = LCG::with_seed(len as u64);
+        (0..len).map(|_| random.next() >> shift).collect()
+    }
+
+    #[test]
+    fn merge_rest() {
+        let sorted = [1, 3, 3, 5, 8, 13];
+        let rests: [&[u64]; 5] = [&[], &[0, 0], &[20, 21], &[2, 3, 4, 9, 14], &[1, 13]];
+        for rest in rests {
+            for sorted_len in [0, sorted.len()] {
+                let mut dest = sorted[..sorted_len].to_vec();
+                dest.resize(sorted_len + rest.len(), u64::MAX);
+                super::merge_rest(&mut dest, sorted_len, rest, |&key| key);
+
+                let mut expected = [&sorted[..sorted_len], rest].concat();
+                expected.sort_unstable();
+                assert_eq!(dest, expected, "{rest:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn sort_u64_files() {
+        // not a whole number of slices, so the last keys are merged in; the narrow range has many duplicates
+        let len = 2 * slice_len::<u64>() + 1234;
+        for keys in [random_keys(len, 0), random_keys(len, 52)] {
+            let bytes: Vec<u8> = keys.iter().flat_map(|key| key.to_ne_bytes()).collect();
+            let mut expected = keys;
+            expected.sort_unstable();
+            let expected: Vec<u8> = expected.iter().flat_map(|key| key.to_ne_bytes()).collect();
+
+            let input = TempFile::new("u64-in", &bytes);
+            let output = TempFile::new("u64-out", &[]);
+            for threads in [1, 2] {
+                sort_file(&input.0, Some(&output.0), "u64".parse().unwrap(), threads).unwrap();
+                assert!(std::fs::read(&output.0).unwrap() == expected);
+                // sorting into an output leaves the input as it was
+                assert!(std::fs::read(&input.0).unwrap() == bytes);
+            }
+            sort_file(&input.0, None, "u64".parse().unwrap(), 1).unwrap();
+            assert!(std::fs::read(&input.0).unwrap() == expected);
+        }
+    }
+
+    #[test]
+    fn sort_swapped_files() {
+        let len = slice_len::<u64>() + 99;
+        let keys: Vec<i64> = random_keys(len, 0)
+            .into_iter()
+            .map(|key| key as i64)
+            .collect();
+        let input = TempFile::new(
+            "i64be",
+            &keys
+                .iter()
+                .flat_map(|key| key.to_be_bytes())
+                .collect::<Vec<_>>(),
+        );
+        sort_file(&input.0, None, "i64be".parse().unwrap(), 1).unwrap();
+        let mut expected = keys;
+        expected.sort_unstable();
+        let expected: Vec<u8> = expected.iter().flat_map(|key| key.to_be_bytes()).collect();
+        assert!(std::fs::read(&input.0).unwrap() == expected);
+
+        let len = 2 * slice_len::<u32>() + 5;
+        let keys: Vec<u32> = random_keys(len, 32)
+            .into_iter()
+            .map(|key| key as u32)
+            .collect();
+        let input = TempFile::new(
+            "u32be",
+            &keys
+                .iter()
+                .flat_map(|key| key.to_be_bytes())
+                .collect::<Vec<_>>(),
+        );
+        let output = TempFile::new("u32be-out", &[]);
+        sort_file(&input.0, Some(&output.0), "u32be".parse().unwrap(), 2).unwrap();
+        let mut expected = keys;
+        expected.sort_unstable();
+        let expected: Vec<u8> = expected.iter().flat_map(|key| key.to_be_bytes()).collect();
+        assert!(std::fs::read(&output.0).unwrap() == expected);
+    }
+
+    #[test]
+    fn partial_keys() {
+        let input = TempFile::new("partial", &[0; 12]);
+        let err = sort_file(&input.0, None, "u64".parse().unwrap(), 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let empty = TempFile::new("empty", &[]);
+        sort_file(&empty.0, None, "u64".parse().unwrap(), 1).unwrap();
+    }
+}
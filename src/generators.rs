@@ -0,0 +1,211 @@
+use crate::lcg::LCG;
+
+/// A source of pseudorandom keys for benchmarks.
+pub trait KeyGenerator: Clone + Send + Sync {
+    fn from_seed(seed: u64) -> Self;
+
+    fn next_key(&mut self) -> u64;
+
+    /// A generator for the `ix`-th of several non-overlapping streams of `stream_len` keys each.
+    ///
+    /// The result only depends on `ix`, so chunks of a workload can be generated on any number of threads.
+    fn substream(&self, ix: u64, stream_len: u64) -> Self;
+}
+
+impl KeyGenerator for LCG {
+    fn from_seed(seed: u64) -> Self {
+        LCG::with_seed(seed)
+    }
+
+    #[inline(always)]
+    fn next_key(&mut self) -> u64 {
+        self.next()
+    }
+
+    /// The streams are consecutive pieces of this stream.
+    fn substream(&self, ix: u64, stream_len: u64) -> Self {
+        self.advance(ix * stream_len)
+    }
+}
+
+/// O'Neill's PCG64 (XSL RR 128/64): a 128-bit LCG, with a permutation that hides its weak low bits.
+#[derive(Clone)]
+pub struct Pcg64 {
+    state: u128,
+    inc: u128,
+}
+
+impl Pcg64 {
+    const MUL: u128 = 0x2360ED051FC65DA44385DF649FCCF645;
+    const INC: u128 = 0x5851F42D4C957F2D14057B7EF767814F;
+
+    fn step(&mut self) {
+        self.state = self.state.wrapping_mul(Self::MUL).wrapping_add(self.inc);
+    }
+
+    /// Skip the next `n` keys in O(log n) time, in the same way as `LCG::jump`.
+    pub fn jump(&mut self, n: u128) {
+        let (mut mul, mut add) = (1u128, 0u128);
+        let (mut step_mul, mut step_add) = (Self::MUL, self.inc);
+        let mut n = n;
+        while n != 0 {
+            if n & 1 == 1 {
+                mul = mul.wrapping_mul(step_mul);
+                add = add.wrapping_mul(step_mul).wrapping_add(step_add);
+            }
+            step_add = step_add.wrapping_mul(step_mul).wrapping_add(step_add);
+            step_mul = step_mul.wrapping_mul(step_mul);
+            n >>= 1;
+        }
+        self.state = self.state.wrapping_mul(mul).wrapping_add(add);
+    }
+}
+
+impl KeyGenerator for Pcg64 {
+    fn from_seed(seed: u64) -> Self {
+        // as in the reference pcg_setseq_128_srandom_r
+        let mut res = Self {
+            state: 0,
+            inc: Self::INC,
+        };
+        res.step();
+        res.state = res.state.wrapping_add(seed as u128);
+        res.step();
+        res
+    }
+
+    #[inline(always)]
+    fn next_key(&mut self) -> u64 {
+        self.step();
+        let xored = (self.state >> 64) as u64 ^ self.state as u64;
+        xored.rotate_right((self.state >> 122) as u32)
+    }
+
+    /// The streams are consecutive pieces of this stream.
+    fn substream(&self, ix: u64, stream_len: u64) -> Self {
+        let mut res = self.clone();
+        res.jump(ix as u128 * stream_len as u128);
+        res
+    }
+}
+
+/// Blackman and Vigna's xoshiro256**.
+#[derive(Clone)]
+pub struct Xoshiro256StarStar {
+    s: [u64; 4],
+}
+
+impl Xoshiro256StarStar {
+    const JUMP: [u64; 4] = [
+        0x180ec6d33cfd0aba,
+        0xd5a61266f0c9392c,
+        0xa9582618e03fc9aa,
+        0x39abdc4529b1661c,
+    ];
+
+    /// Skip the next 2^128 keys.
+    pub fn jump(&mut self) {
+        let mut s = [0; 4];
+        for word in Self::JUMP {
+            for bit in 0..64 {
+                if word & (1 << bit) != 0 {
+                    for (s, cur) in s.iter_mut().zip(self.s) {
+                        *s ^= cur;
+                    }
+                }
+                self.next_key();
+            }
+        }
+        self.s = s;
+    }
+}
+
+impl KeyGenerator for Xoshiro256StarStar {
+    fn from_seed(seed: u64) -> Self {
+        // the authors recommend expanding the seed with splitmix64
+        let mut x = seed;
+        let mut splitmix64 = || {
+            x = x.wrapping_add(0x9e3779b97f4a7c15);
+            let mut z = x;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+            z ^ (z >> 31)
+        };
+        Self {
+            s: [splitmix64(), splitmix64(), splitmix64(), splitmix64()],
+        }
+    }
+
+    #[inline(always)]
+    fn next_key(&mut self) -> u64 {
+        let res = self.s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = self.s[1] << 17;
+        self.s[2] ^= self.s[0];
+        self.s[3] ^= self.s[1];
+        self.s[1] ^= self.s[2];
+        self.s[0] ^= self.s[3];
+        self.s[2] ^= t;
+        self.s[3] = self.s[3].rotate_left(45);
+        res
+    }
+
+    /// Each stream starts `2^128` keys after the previous one, so they never overlap, but unlike the other
+    /// generators they are not consecutive pieces of this stream.
+    fn substream(&self, ix: u64, _stream_len: u64) -> Self {
+        let mut res = self.clone();
+        for _ in 0..ix {
+            res.jump();
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn take<G: KeyGenerator>(gen: &mut G, n: usize) -> Vec<u64> {
+        (0..n).map(|_| gen.next_key()).collect()
+    }
+
+    #[test]
+    fn pcg64_jump_matches_next() {
+        for n in [0, 1, 1000, 1_000_003] {
+            let mut stepped = Pcg64::from_seed(3);
+            take(&mut stepped, n);
+            let mut jumped = Pcg64::from_seed(3);
+            jumped.jump(n as u128);
+            assert_eq!(jumped.next_key(), stepped.next_key(), "n = {n}");
+        }
+    }
+
+    #[test]
+    fn substreams_are_consecutive() {
+        fn check<G: KeyGenerator>(gen: G) {
+            let whole = take(&mut gen.clone(), 4 * 100);
+            let streams: Vec<u64> = (0..4)
+                .flat_map(|ix| take(&mut gen.substream(ix, 100), 100))
+                .collect();
+            assert!(streams == whole);
+        }
+        check(LCG::with_seed(1));
+        check(Pcg64::from_seed(1));
+    }
+
+    #[test]
+    fn xoshiro_substreams_do_not_repeat() {
+        let gen = Xoshiro256StarStar::from_seed(1);
+        let first = take(&mut gen.substream(0, 100), 100);
+        assert!(first == take(&mut gen.clone(), 100));
+        let second = take(&mut gen.substream(1, 100), 100);
+        assert!(second.iter().all(|key| !first.contains(key)));
+        assert!(take(&mut gen.substream(1, 5), 100) == second);
+    }
+
+    #[test]
+    fn seeds_differ() {
+        assert!(take(&mut Pcg64::from_seed(0), 10) != take(&mut Pcg64::from_seed(1), 10));
+        let xoshiro = |seed| take(&mut Xoshiro256StarStar::from_seed(seed), 10);
+        assert!(xoshiro(0) != xoshiro(1));
+    }
+}
@@ -2,6 +2,7 @@
 const A: u64 = 6364136223846793005;
 const C: u64 = 1442695040888963407;
 
+#[derive(Clone)]
 pub struct LCG {
     x: u64,
 }
@@ -11,6 +12,11 @@ impl LCG {
         Self { x: A }
     }
 
+    /// Start the stream at `seed`, which is the first value returned by `next`.
+    pub const fn with_seed(seed: u64) -> Self {
+        Self { x: seed }
+    }
+
     pub fn next(&mut self) -> u64 {
         let ret = self.x;
         self.x = ret.wrapping_mul(A).wrapping_add(C);
@@ -22,4 +28,74 @@ impl LCG {
         let x = ret.wrapping_mul(A).wrapping_add(C);
         (ret, Self { x })
     }
+
+    /// Skip the next `n` values in O(log n) time.
+    pub fn jump(&mut self, n: u64) {
+        // Brown, "Random Number Generation with Arbitrary Strides": n steps of x -> ax + c is itself an affine map,
+        // which we build by repeated squaring of the one-step map.
+        let (mut mul, mut add) = (1u64, 0u64);
+        let (mut step_mul, mut step_add) = (A, C);
+        let mut n = n;
+        while n != 0 {
+            if n & 1 == 1 {
+                mul = mul.wrapping_mul(step_mul);
+                add = add.wrapping_mul(step_mul).wrapping_add(step_add);
+            }
+            step_add = step_add.wrapping_mul(step_mul).wrapping_add(step_add);
+            step_mul = step_mul.wrapping_mul(step_mul);
+            n >>= 1;
+        }
+        self.x = self.x.wrapping_mul(mul).wrapping_add(add);
+    }
+
+    /// A copy of this stream, advanced by `n` values.
+    pub fn advance(&self, n: u64) -> Self {
+        let mut res = self.clone();
+        res.jump(n);
+        res
+    }
+
+    /// Split the next `num_streams * stream_len` values into consecutive substreams of `stream_len` values each.
+    ///
+    /// Drawing `stream_len` values from each substream in order gives exactly the values this stream would have
+    /// produced, so work can be spread over threads without changing the result.
+    pub fn substreams(&self, num_streams: usize, stream_len: u64) -> impl Iterator<Item = Self> + '_ {
+        (0..num_streams as u64).map(move |ix| self.advance(ix * stream_len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn advance_matches_next() {
+        for n in [0, 1, 2, 1000, 1_000_003] {
+            let mut stepped = LCG::with_seed(42);
+            for _ in 0..n {
+                stepped.next();
+            }
+            assert_eq!(LCG::with_seed(42).advance(n).next(), stepped.next(), "n = {n}");
+        }
+    }
+
+    #[test]
+    fn jumps_compose() {
+        let lcg = LCG::new();
+        let (a, b) = (1 << 40, (1 << 63) + 12345);
+        assert_eq!(lcg.advance(a).advance(b).next(), lcg.advance(a.wrapping_add(b)).next());
+        // the period is 2^64
+        assert_eq!(lcg.advance(u64::MAX).advance(1).next(), lcg.clone().next());
+    }
+
+    #[test]
+    fn substreams_are_consecutive() {
+        let lcg = LCG::with_seed(7);
+        let mut whole = lcg.clone();
+        for mut stream in lcg.substreams(5, 333) {
+            for _ in 0..333 {
+                assert_eq!(stream.next(), whole.next());
+            }
+        }
+    }
 }
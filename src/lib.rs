@@ -2,7 +2,21 @@
 #![feature(const_result_drop)]
 #![feature(const_option)]
 
+pub mod allocators;
+pub mod external;
+pub mod files;
+pub mod generators;
 pub mod lcg;
+pub mod numa;
+pub mod observer;
+pub mod perf;
+pub mod quantile;
 pub mod radix_naive;
+pub mod records;
 pub mod scheduler;
 pub mod splitters;
+pub mod streaming;
+pub mod strings;
+pub mod transforms;
+pub mod verify;
+pub mod workloads;
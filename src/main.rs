@@ -2,161 +2,1320 @@
 #![feature(const_result_drop)]
 #![feature(const_option)]
 
+mod cli;
+
 use std::{
-    cmp::Ordering,
+    alloc::Layout,
+    collections::{BTreeMap, HashMap},
+    fmt,
+    fs::OpenOptions,
+    io::Write,
     mem::{size_of, MaybeUninit},
+    str::FromStr,
     time::Instant,
 };
 
+use cli::{default_threads, option_value, parse_positive, parse_size, Size};
 use pbs::{
+    allocators::{Arena, GlobalSlices, MmapArena, NumaSlices, Pages, SliceAllocator},
+    generators::{KeyGenerator, Pcg64, Xoshiro256StarStar},
     lcg::LCG,
-    radix_naive::radix_sort,
-    scheduler::{Scheduler, MAX_LEVEL_SPLIT, SLICE_SIZE_BYTES},
+    numa::Topology,
+    observer::{Observer, Phase},
+    perf::{Counters, Event},
+    radix_naive::radix_sort_observed,
+    scheduler::{
+        OverBudget, Scheduler, SplitStats, MAX_LEVEL_SPLIT, NUM_BUCKETS, SLICE_SIZE_BYTES,
+    },
     splitters::ScalarSplitter,
+    transforms::Identity,
+    verify::{verify_sorted, Checksum, VerifyRuns},
+    workloads::Distribution,
 };
 
-// number of u64 in one GB (power of two)
-const BUF_SIZE_BYTES: usize = 1 << 30;
-const BUF_SIZE: usize = BUF_SIZE_BYTES / size_of::<u64>();
+// number of keys taken from each substream of the generator. This is fixed so that the input does not depend on
+// the number of threads.
+const GEN_CHUNK_LEN: usize = 1 << 20;
 
-fn main() {
-    _main_test2();
-}
+const USAGE: &str = "\
+Usage: pbs-bench [OPTIONS]
+       pbs-bench compare <OLD> <NEW> [--threshold <PERCENT>]
+
+Options:
+  --engine <ENGINE>  scheduler, scheduler-in-place (one thread, no output buffer), scheduler-visit (one thread,
+                     checks each sorted run as it is visited instead of writing it out), naive, std
+                     (sort_unstable) or std-stable (sort) [default: scheduler]
+  --baseline <ENGINES>
+                     comma-separated engines to also run on the same input, reporting the speedup over each
+  --size <SIZE>      size of the input, e.g. 4GiB, 500MB or 65536 (bytes) [default: 1GiB]
+  --dist <DIST>      uniform, sorted, reverse, nearly-sorted, all-equal, few-unique, zipf, gaussian,
+                     narrow[:<BITS>] (keys below 2^BITS, 20 by default) or staggered [default: uniform]
+  --gen <GEN>        lcg, pcg64 or xoshiro256** [default: lcg]
+  --threads <N>      threads used to generate the input, and by the scheduler engine [default: all cores]
+  --allocator <ALLOC>
+                     where the scheduler engine gets its slices: global (the global allocator), arena (one
+                     allocation up front), mmap (an anonymous mapping, Linux only), mmap-huge (the same on
+                     huge pages where available) or numa (a mapping on each NUMA node, used by the threads on
+                     that node) [default: global]
+  --memory-budget <SIZE>
+                     the most memory the scheduler engine may take for slices, beyond the input; buckets that do
+                     not fit are sorted in place
+  --repeat <N>       number of timed runs [default: 1]
+  --format <FORMAT>  human, json (one object per run, per line) or csv [default: human]
+  --output <FILE>    append the results to FILE instead of printing them
+  --stats            show the time spent on each level of the scheduler engine, and its slice usage
+  --progress         show the progress of the scheduler and naive engines
+  --pin              pin the threads of the scheduler engine to CPUs, spread evenly over the NUMA nodes
+  --perf             count instructions, cache, branch and dTLB misses in each phase of the sort (Linux only)
+  -h, --help         print this message
 
-fn _main_test2() {
-    let mut lcg = LCG::new();
+compare reads two files of json or csv results, and compares the median time of each configuration that is in
+both. It exits with status 1 if any of them got slower by more than the threshold [default: 5%].";
+
+fn main() {
+    let mut cli_args = std::env::args().skip(1).peekable();
+    if cli_args.peek().is_some_and(|arg| arg == "compare") {
+        cli_args.next();
+        match compare(cli_args) {
+            Ok(false) => return,
+            Ok(true) => std::process::exit(1),
+            Err(err) => {
+                eprintln!("error: {err}\n\n{USAGE}");
+                std::process::exit(2);
+            }
+        }
+    }
 
-    let buf = {
-        let mut buf = Box::new_uninit_slice(BUF_SIZE);
-        for el in buf.iter_mut() {
-            el.write(lcg.next());
+    let args = match Args::parse(cli_args) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            println!("{USAGE}");
+            return;
+        }
+        Err(err) => {
+            eprintln!("error: {err}\n\n{USAGE}");
+            std::process::exit(2);
         }
-        unsafe { buf.assume_init() }
     };
 
-    let mut buf = std::hint::black_box(buf);
-
-    eprintln!("Splitting");
-    let start = Instant::now();
-
-    radix_sort(&mut buf);
-
-    let end = Instant::now();
-    eprintln!("Done splitting");
-
-    let secs = end.duration_since(start).as_secs_f64();
-    let speed = (BUF_SIZE as f64 / (1 << 27) as f64) / secs;
-    let item_speed = speed / size_of::<u64>() as f64;
-    println!("Time: {secs:.2} s, Speed: {speed:.2} GB/s = {item_speed:.2} B keys/s");
-
-    let buf = std::hint::black_box(buf);
-
-    assert!(check_split(&buf, 64));
-}
-
-fn _main_test1() {
-    const BUF_LAYOUT: std::alloc::Layout =
-        std::alloc::Layout::from_size_align(BUF_SIZE_BYTES, SLICE_SIZE_BYTES)
-            .ok()
-            .expect("BUF_SIZE_BYTES and SLICE_SIZE_BYTES should be powers of two");
-    let mut lcg = LCG::new();
-    /*
-     * THIS IS NOT PROPERLY ALIGNED!!
-     **/
-    // let mut buf = {
-    //     let mut buf: Box<[MaybeUninit<u64>]> = Box::new_uninit_slice(BUF_SIZE);
-    //     for el in buf.iter_mut() {
-    //         el.write(lcg.next());
-    //     }
-    //     unsafe { buf.assume_init() }
-    // };
-    let buf: Box<[u64]> = {
-        // must have at least SLICE_SIZE_BYTES alignment!
-        let ptr = unsafe { std::alloc::alloc(BUF_LAYOUT) } as *mut MaybeUninit<_>;
-        let mut buf = unsafe { Box::from_raw(std::slice::from_raw_parts_mut(ptr, BUF_SIZE)) };
-        for el in buf.iter_mut() {
-            el.write(lcg.next());
-        }
-        unsafe { buf.assume_init() }
+    let (mut out, is_empty): (Box<dyn Write>, bool) = match &args.output {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .unwrap_or_else(|err| panic!("could not open {path}: {err}"));
+            let is_empty = file.metadata().map_or(true, |meta| meta.len() == 0);
+            (Box::new(file), is_empty)
+        }
+        None => (Box::new(std::io::stdout()), true),
     };
+    if args.format == Format::Csv && is_empty {
+        writeln!(out, "{}", RunRecord::FIELDS.join(",")).unwrap();
+    }
+
+    // the engine being measured comes first. Runs of each engine are interleaved, so that they are all affected
+    // alike by anything else happening on the machine.
+    if args.perf {
+        if let Err(err) = PhaseCounters::new() {
+            eprintln!("error: performance counters are unavailable: {err}");
+            std::process::exit(2);
+        }
+    }
+
+    let engines = std::iter::once(args.engine)
+        .chain(args.baselines.iter().copied())
+        .collect::<Vec<_>>();
+    let mut times = vec![Vec::with_capacity(args.repeat); engines.len()];
+    for run in 0..args.repeat {
+        for (&engine, times) in engines.iter().zip(&mut times) {
+            let result = engine.run(&args);
+            let record = RunRecord {
+                engine: engine.to_string(),
+                dist: args.dist_name.clone(),
+                gen: args.gen.to_string(),
+                size: args.size,
+                threads: args.threads,
+                run,
+                secs: result.secs,
+            };
+            match args.format {
+                Format::Human => {
+                    let secs = record.secs;
+                    let speed = record.gib_per_s();
+                    let item_speed = speed / size_of::<u64>() as f64;
+                    writeln!(out, "{engine} run {run}: Time: {secs:.2} s, Speed: {speed:.2} GB/s = {item_speed:.2} B keys/s")
+                }
+                Format::Json => writeln!(out, "{}", record.to_json()),
+                Format::Csv => writeln!(out, "{}", record.to_csv()),
+            }
+            .unwrap();
+            for line in result.details() {
+                if args.format == Format::Human {
+                    writeln!(out, "  {line}").unwrap();
+                } else {
+                    eprintln!("  {line}");
+                }
+            }
+            times.push(record.secs);
+        }
+    }
+
+    let mut summary = vec![];
+    for (engine, times) in engines.iter().zip(&mut times) {
+        times.sort_by(f64::total_cmp);
+        summary.push(format!(
+            "{engine} on {} of {}: min {:.2} s, median {:.2} s, max {:.2} s",
+            Size(args.size),
+            args.dist_name,
+            times[0],
+            median(times),
+            times[times.len() - 1],
+        ));
+    }
+    for (baseline, baseline_times) in engines.iter().zip(&times).skip(1) {
+        let speedup = median(baseline_times) / median(&times[0]);
+        summary.push(format!(
+            "{} is {speedup:.2}x as fast as {baseline} (by median time)",
+            args.engine
+        ));
+    }
+    for line in summary {
+        if args.format == Format::Human {
+            writeln!(out, "{line}").unwrap();
+        } else {
+            // keep the results parseable
+            eprintln!("{line}");
+        }
+    }
+}
+
+/// The median of `times`, which must be sorted.
+fn median(times: &[f64]) -> f64 {
+    let mid = times.len() / 2;
+    if times.len().is_multiple_of(2) {
+        (times[mid - 1] + times[mid]) / 2.0
+    } else {
+        times[mid]
+    }
+}
+
+struct Args {
+    engine: Engine,
+    /// other engines to compare `engine` against
+    baselines: Vec<Engine>,
+    /// in bytes, a multiple of `size_of::<u64>()`
+    size: usize,
+    dist: Distribution,
+    /// as given on the command line
+    dist_name: String,
+    gen: Generator,
+    threads: usize,
+    allocator: Allocator,
+    /// in bytes
+    memory_budget: Option<usize>,
+    repeat: usize,
+    format: Format,
+    output: Option<String>,
+    /// count hardware events in each phase of the sort
+    perf: bool,
+    /// collect `SplitStats` in the scheduler engine
+    stats: bool,
+    /// show the progress of the scheduler and naive engines on stderr
+    progress: bool,
+    /// pin the threads of `split_parallel` to CPUs on every node
+    pin: bool,
+}
+
+impl Args {
+    /// Parse the command line (without the program name). Returns `None` if we were asked for help.
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Option<Self>, String> {
+        let mut res = Self {
+            engine: Engine::Scheduler,
+            baselines: vec![],
+            size: 1 << 30,
+            dist: Distribution::Uniform,
+            dist_name: "uniform".to_string(),
+            gen: Generator::Lcg,
+            threads: default_threads(),
+            allocator: Allocator::Global,
+            memory_budget: None,
+            repeat: 1,
+            format: Format::Human,
+            output: None,
+            perf: false,
+            stats: false,
+            progress: false,
+            pin: false,
+        };
+
+        while let Some(arg) = args.next() {
+            if arg == "-h" || arg == "--help" {
+                return Ok(None);
+            }
+            if arg == "--perf" || arg == "--stats" || arg == "--progress" || arg == "--pin" {
+                res.perf |= arg == "--perf";
+                res.stats |= arg == "--stats";
+                res.progress |= arg == "--progress";
+                res.pin |= arg == "--pin";
+                continue;
+            }
+            let (opt, value) = option_value(arg, &mut args)?;
+            match &opt[..] {
+                "--engine" => res.engine = value.parse()?,
+                "--baseline" => {
+                    res.baselines = value.split(',').map(str::parse).collect::<Result<_, _>>()?
+                }
+                "--size" => res.size = parse_size(&value)?,
+                "--dist" => {
+                    res.dist = parse_dist(&value)?;
+                    res.dist_name = value;
+                }
+                "--gen" => res.gen = value.parse()?,
+                "--threads" => res.threads = parse_positive(&opt, &value)?,
+                "--allocator" => res.allocator = value.parse()?,
+                "--memory-budget" => res.memory_budget = Some(parse_size(&value)?),
+                "--repeat" => res.repeat = parse_positive(&opt, &value)?,
+                "--format" => res.format = value.parse()?,
+                "--output" => res.output = Some(value),
+                _ => return Err(format!("unknown option {opt}")),
+            }
+        }
+
+        if res.size == 0 || !res.size.is_multiple_of(size_of::<u64>()) {
+            return Err(format!(
+                "size must be a positive multiple of {} bytes",
+                size_of::<u64>()
+            ));
+        }
+        let engines = || std::iter::once(&res.engine).chain(&res.baselines);
+        let uses_scheduler = engines().any(|engine| engine.uses_scheduler());
+        if uses_scheduler && !res.size.is_multiple_of(SLICE_SIZE_BYTES) {
+            return Err(format!(
+                "the scheduler engine needs a size that is a multiple of {}",
+                Size(SLICE_SIZE_BYTES)
+            ));
+        }
+
+        if res.memory_budget.is_some() {
+            if let Some(engine) = engines().find(|&&engine| {
+                engine == Engine::SchedulerInPlace || engine == Engine::SchedulerVisit
+            }) {
+                return Err(format!(
+                    "the {engine} engine does not support --memory-budget"
+                ));
+            }
+        }
+
+        if res.perf && uses_scheduler && res.threads > 1 {
+            return Err(
+                "--perf only counts events on the main thread, so the scheduler engine needs --threads 1"
+                    .to_string(),
+            );
+        }
+
+        Ok(Some(res))
+    }
+
+    fn len(&self) -> usize {
+        self.size / size_of::<u64>()
+    }
+}
 
-    let output: Box<[u64]> = {
-        // must have at least SLICE_SIZE_BYTES alignment!
-        let ptr = unsafe { std::alloc::alloc(BUF_LAYOUT) } as *mut MaybeUninit<_>;
-        let mut buf = unsafe { Box::from_raw(std::slice::from_raw_parts_mut(ptr, BUF_SIZE)) };
-        for el in buf.iter_mut() {
-            el.write(0);
+/// Parse the name of a distribution, using typical parameters for those that need them.
+fn parse_dist(s: &str) -> Result<Distribution, String> {
+    let dist = match s {
+        "uniform" => Distribution::Uniform,
+        "sorted" => Distribution::Sorted,
+        "reverse" | "reverse-sorted" => Distribution::ReverseSorted,
+        "nearly-sorted" => Distribution::NearlySorted {
+            fraction_unsorted: 0.01,
+        },
+        "all-equal" => Distribution::AllEqual,
+        "few-unique" => Distribution::FewUnique { unique: 1 << 10 },
+        "zipf" => Distribution::Zipf {
+            unique: 1 << 20,
+            exponent: 1.0,
+        },
+        "gaussian" => Distribution::Gaussian { std_dev_bits: 48 },
+        "narrow" => Distribution::NarrowRange { bits: 20 },
+        _ if s.starts_with("narrow:") => {
+            let bits = &s["narrow:".len()..];
+            Distribution::NarrowRange {
+                bits: bits
+                    .parse()
+                    .map_err(|_| format!("invalid number of bits {bits:?} in {s:?}"))?,
+            }
         }
-        unsafe { buf.assume_init() }
+        "staggered" => Distribution::Staggered { blocks: 64 },
+        _ => return Err(format!("unknown distribution {s:?}")),
     };
+    dist.validate()?;
+    Ok(dist)
+}
 
-    let (mut buf, mut output) = std::hint::black_box((buf, output));
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Human,
+    Json,
+    Csv,
+}
 
-    let mut sched = Scheduler::new();
-    let mut splitter = ScalarSplitter::default();
+impl FromStr for Format {
+    type Err = String;
 
-    eprintln!("Splitting");
-    let start = Instant::now();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(Format::Human),
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("unknown format {s:?}, expected human, json or csv")),
+        }
+    }
+}
+
+/// The result of one timed run, as written by `--format json` and `--format csv`.
+#[derive(Debug)]
+struct RunRecord {
+    engine: String,
+    dist: String,
+    gen: String,
+    /// in bytes
+    size: usize,
+    threads: usize,
+    run: usize,
+    secs: f64,
+}
+
+impl RunRecord {
+    const FIELDS: [&'static str; 9] = [
+        "engine",
+        "dist",
+        "gen",
+        "size",
+        "threads",
+        "run",
+        "secs",
+        "gib_per_s",
+        "keys_per_s",
+    ];
+
+    fn gib_per_s(&self) -> f64 {
+        (self.size as f64 / (1 << 30) as f64) / self.secs
+    }
+
+    fn keys_per_s(&self) -> f64 {
+        (self.size / size_of::<u64>()) as f64 / self.secs
+    }
 
-    sched.split(&mut buf, &mut output, &mut splitter);
+    /// The values of `FIELDS`, with strings quoted as in JSON.
+    fn values(&self) -> [String; 9] {
+        [
+            json_string(&self.engine),
+            json_string(&self.dist),
+            json_string(&self.gen),
+            self.size.to_string(),
+            self.threads.to_string(),
+            self.run.to_string(),
+            format!("{:.6}", self.secs),
+            format!("{:.6}", self.gib_per_s()),
+            format!("{:.0}", self.keys_per_s()),
+        ]
+    }
 
-    let end = Instant::now();
-    eprintln!("Done splitting");
+    fn to_json(&self) -> String {
+        let fields = Self::FIELDS
+            .iter()
+            .zip(self.values())
+            .map(|(field, value)| format!("\"{field}\": {value}"))
+            .collect::<Vec<_>>();
+        format!("{{{}}}", fields.join(", "))
+    }
 
-    let secs = end.duration_since(start).as_secs_f64();
-    let speed = (BUF_SIZE as f64 / (1 << 27) as f64) / secs;
-    let item_speed = speed / size_of::<u64>() as f64;
-    println!("Time: {secs:.2} s, Speed: {speed:.2} GB/s = {item_speed:.2} B keys/s");
+    fn to_csv(&self) -> String {
+        self.values().join(",")
+    }
+
+    /// Read a record back from its fields. Derived fields (like `gib_per_s`) are ignored.
+    fn from_fields(fields: &HashMap<String, String>) -> Result<Self, String> {
+        let get = |name: &str| {
+            fields
+                .get(name)
+                .ok_or_else(|| format!("missing field {name:?}"))
+        };
+        let get_num = |name: &str| {
+            get(name)?
+                .parse::<f64>()
+                .map_err(|_| format!("field {name:?} is not a number"))
+        };
+        Ok(Self {
+            engine: get("engine")?.clone(),
+            dist: get("dist")?.clone(),
+            gen: get("gen")?.clone(),
+            size: get_num("size")? as usize,
+            threads: get_num("threads")? as usize,
+            run: get_num("run")? as usize,
+            secs: get_num("secs")?,
+        })
+    }
+}
 
-    let (buf, output) = std::hint::black_box((buf, output));
+fn json_string(s: &str) -> String {
+    let mut res = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            c if c.is_control() => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
 
-    // let splits = sched.get_splits();
+/// Parse one flat JSON object whose values are strings or numbers, as written by `RunRecord::to_json`.
+fn parse_json_object(line: &str) -> Result<HashMap<String, String>, String> {
+    let err = || format!("cannot parse {line:?} as a result");
+    let mut res = HashMap::new();
+    let mut chars = line
+        .trim()
+        .strip_prefix('{')
+        .ok_or_else(err)?
+        .chars()
+        .peekable();
 
-    assert!(check_split(&output, 8 * MAX_LEVEL_SPLIT));
+    let parse_string = |chars: &mut std::iter::Peekable<std::str::Chars>| -> Option<String> {
+        let mut s = String::new();
+        loop {
+            match chars.next()? {
+                '"' => return Some(s),
+                '\\' => match chars.next()? {
+                    'u' => {
+                        let hex = (0..4).map(|_| chars.next()).collect::<Option<String>>()?;
+                        s.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                    }
+                    'n' => s.push('\n'),
+                    't' => s.push('\t'),
+                    c => s.push(c),
+                },
+                c => s.push(c),
+            }
+        }
+    };
 
-    // we cannot let the Box free its data, since we alloced the memory ourselves
-    unsafe {
-        std::alloc::dealloc(Box::<[u64]>::into_raw(buf) as *mut u8, BUF_LAYOUT);
-        std::alloc::dealloc(Box::<[u64]>::into_raw(output) as *mut u8, BUF_LAYOUT);
+    loop {
+        match chars.find(|c| !c.is_whitespace()).ok_or_else(err)? {
+            '}' => return Ok(res),
+            '"' => (),
+            _ => return Err(err()),
+        }
+        let key = parse_string(&mut chars).ok_or_else(err)?;
+        if chars.find(|c| !c.is_whitespace()) != Some(':') {
+            return Err(err());
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let value = if chars.next_if_eq(&'"').is_some() {
+            parse_string(&mut chars).ok_or_else(err)?
+        } else {
+            let mut value = String::new();
+            while let Some(c) = chars.next_if(|&c| c != ',' && c != '}') {
+                value.push(c);
+            }
+            value.trim().to_string()
+        };
+        res.insert(key, value);
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        chars.next_if_eq(&',');
     }
 }
 
-fn check_split(buf: &[u64], num_bits: u8) -> bool {
-    let mask = if num_bits == 64 {
-        !0
+/// Read the results in `path`, which is either JSON (one object per line) or CSV with a header.
+fn read_records(path: &str) -> Result<Vec<RunRecord>, String> {
+    let text =
+        std::fs::read_to_string(path).map_err(|err| format!("could not read {path}: {err}"))?;
+    let mut lines = text.lines().filter(|line| !line.trim().is_empty());
+    let in_file = |err| format!("{path}: {err}");
+
+    if text.trim_start().starts_with('{') {
+        lines
+            .map(|line| RunRecord::from_fields(&parse_json_object(line)?))
+            .collect::<Result<_, _>>()
+            .map_err(in_file)
     } else {
-        (1 << num_bits) - 1
+        let header = lines
+            .next()
+            .unwrap_or_default()
+            .split(',')
+            .collect::<Vec<_>>();
+        lines
+            .map(|line| {
+                // we only quote strings that have no commas or quotes in them
+                let values = line
+                    .split(',')
+                    .map(|value| value.trim_matches('"').to_string());
+                let fields = header
+                    .iter()
+                    .map(|name| name.to_string())
+                    .zip(values)
+                    .collect();
+                RunRecord::from_fields(&fields)
+            })
+            .collect::<Result<_, _>>()
+            .map_err(in_file)
+    }
+}
+
+/// Run the `compare` subcommand, returning whether there were any regressions.
+fn compare(mut args: impl Iterator<Item = String>) -> Result<bool, String> {
+    let mut paths = vec![];
+    let mut threshold = 5.0;
+    while let Some(arg) = args.next() {
+        if let Some(value) = arg.strip_prefix("--threshold") {
+            let value = match value.strip_prefix('=') {
+                Some(value) => value.to_string(),
+                None if value.is_empty() => args.next().ok_or("missing value for --threshold")?,
+                None => return Err(format!("unknown option {arg}")),
+            };
+            threshold = value
+                .trim_end_matches('%')
+                .parse::<f64>()
+                .map_err(|_| format!("invalid threshold {value:?}"))?;
+        } else {
+            paths.push(arg);
+        }
+    }
+    let [old, new] = &paths[..] else {
+        return Err("compare needs exactly two result files".to_string());
     };
-    let mask = mask << (64 - num_bits);
-    let mut total = 0usize;
-    let mut incorrect = 0usize;
-    let mut current = 0;
-
-    for (ix, key) in buf
-        .iter() /*.flat_map(|slice| slice.iter())*/
-        .enumerate()
+
+    // the times of every run of each configuration
+    type Config = (String, String, String, usize, usize);
+    let group = |records: Vec<RunRecord>| {
+        let mut res = BTreeMap::<Config, Vec<f64>>::new();
+        for record in records {
+            let config = (
+                record.engine,
+                record.dist,
+                record.gen,
+                record.size,
+                record.threads,
+            );
+            res.entry(config).or_default().push(record.secs);
+        }
+        for times in res.values_mut() {
+            times.sort_by(f64::total_cmp);
+        }
+        res
+    };
+    let old_times = group(read_records(old)?);
+    let new_times = group(read_records(new)?);
+
+    let mut regressed = false;
+    for (config, new) in &new_times {
+        let (engine, dist, gen, size, threads) = config;
+        let name = format!(
+            "{engine} on {} of {dist} from {gen}, {threads} threads",
+            Size(*size)
+        );
+        let Some(old) = old_times.get(config) else {
+            println!("{name}: only in {}", paths[1]);
+            continue;
+        };
+        let (old, new) = (median(old), median(new));
+        let change = (new / old - 1.0) * 100.0;
+        let is_regression = change > threshold;
+        regressed |= is_regression;
+        println!(
+            "{name}: {old:.3} s -> {new:.3} s ({change:+.1}%){}",
+            if is_regression { "  REGRESSION" } else { "" }
+        );
+    }
+    for config in old_times
+        .keys()
+        .filter(|config| !new_times.contains_key(config))
     {
-        let bucket = key & mask;
-        total += 1;
-        match current.cmp(&bucket) {
-            Ordering::Less => {
-                current = bucket;
+        let (engine, dist, gen, size, threads) = config;
+        println!(
+            "{engine} on {} of {dist} from {gen}, {threads} threads: only in {}",
+            Size(*size),
+            paths[0]
+        );
+    }
+
+    Ok(regressed)
+}
+
+/// A sort that pbs-bench can time, chosen with `--engine`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Engine {
+    /// `Scheduler::split` (or `split_parallel`), on slice-aligned buffers
+    Scheduler,
+    /// `Scheduler::split_in_place`, on a slice-aligned buffer
+    SchedulerInPlace,
+    /// `Scheduler::split_visit`, checking the sorted runs as they are visited rather than writing them out
+    SchedulerVisit,
+    /// `radix_naive::radix_sort`
+    Naive,
+    /// `slice::sort_unstable`
+    Std,
+    /// `slice::sort`
+    StdStable,
+}
+
+impl FromStr for Engine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "scheduler" => Ok(Engine::Scheduler),
+            "scheduler-in-place" => Ok(Engine::SchedulerInPlace),
+            "scheduler-visit" => Ok(Engine::SchedulerVisit),
+            "naive" => Ok(Engine::Naive),
+            "std" => Ok(Engine::Std),
+            "std-stable" => Ok(Engine::StdStable),
+            _ => Err(format!(
+                "unknown engine {s:?}, expected scheduler, scheduler-in-place, scheduler-visit, naive, std or std-stable"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Engine::Scheduler => "scheduler",
+            Engine::SchedulerInPlace => "scheduler-in-place",
+            Engine::SchedulerVisit => "scheduler-visit",
+            Engine::Naive => "naive",
+            Engine::Std => "std",
+            Engine::StdStable => "std-stable",
+        })
+    }
+}
+
+impl Engine {
+    fn uses_scheduler(self) -> bool {
+        matches!(
+            self,
+            Engine::Scheduler | Engine::SchedulerInPlace | Engine::SchedulerVisit
+        )
+    }
+
+    /// Generate a fresh input, sort it, check the result against the input, and measure the sort.
+    ///
+    /// Generating the input is deterministic, so every engine sorts the same keys.
+    fn run(self, args: &Args) -> RunResult {
+        let len = args.len();
+        let mut perf = args
+            .perf
+            .then(|| PhaseCounters::new().expect("performance counters were available before"));
+        match self {
+            Engine::Scheduler | Engine::SchedulerInPlace | Engine::SchedulerVisit => {
+                let in_place = self == Engine::SchedulerInPlace;
+                let mut visited = (self == Engine::SchedulerVisit).then(VerifyRuns::new);
+                let buf = {
+                    let mut buf = alloc_aligned(len);
+                    args.gen.generate(&mut buf, args.dist, args.threads);
+                    unsafe { buf.assume_init() }
+                };
+                let checksum = Checksum::of(&buf[..]);
+                let output = (!in_place && visited.is_none()).then(|| {
+                    let mut buf = alloc_aligned(len);
+                    // touch every page now, so that page faults are not part of the timing
+                    for el in buf.iter_mut() {
+                        el.write(0);
+                    }
+                    unsafe { buf.assume_init() }
+                });
+
+                let (mut buf, mut output) = std::hint::black_box((buf, output));
+
+                let (allocator, pages) = args.allocator.create(args);
+                let (secs, stats) = {
+                    let mut sched = Scheduler::new();
+                    sched.set_allocator(&*allocator);
+                    sched.set_observer(BenchObserver {
+                        perf: perf.as_mut(),
+                        progress: args.progress,
+                    });
+                    if args.stats {
+                        sched.enable_stats();
+                    }
+                    if args.pin {
+                        sched.set_pinned_cpus(Topology::detect().spread(args.threads));
+                    }
+                    if let Some(budget) = args.memory_budget {
+                        sched.set_memory_budget(budget, OverBudget::SortInPlace);
+                    }
+                    let splitter = ScalarSplitter::new();
+
+                    let start = Instant::now();
+                    let stats = match &mut output {
+                        None => match &mut visited {
+                            Some(visited) => {
+                                sched.split_visit(&mut buf, &mut splitter.clone(), |run| {
+                                    visited.add(run)
+                                })
+                            }
+                            None => sched.split_in_place(&mut buf, &mut splitter.clone()),
+                        },
+                        Some(output) => {
+                            if args.threads > 1 {
+                                sched.split_parallel(&mut buf, output, &splitter, args.threads)
+                            } else {
+                                sched.split(&mut buf, output, &mut splitter.clone())
+                            }
+                        }
+                    };
+                    (start.elapsed().as_secs_f64(), stats)
+                };
+
+                let (buf, output) = std::hint::black_box((buf, output));
+                let verified = match visited {
+                    Some(visited) => visited.finish(checksum),
+                    None => verify_sorted(output.as_deref().unwrap_or(&buf), checksum),
+                };
+                if let Err(err) = verified {
+                    panic!("{self} did not sort correctly: {err}");
+                }
+
+                // we cannot let the Box free its data, since we alloced the memory ourselves
+                unsafe {
+                    dealloc_aligned(buf);
+                    if let Some(output) = output {
+                        dealloc_aligned(output);
+                    }
+                }
+                RunResult {
+                    secs,
+                    phase_counts: perf.map_or(vec![], |perf| perf.totals),
+                    stats,
+                    pages,
+                }
+            }
+            Engine::Naive | Engine::Std | Engine::StdStable => {
+                let buf = {
+                    let mut buf = Box::new_uninit_slice(len);
+                    args.gen.generate(&mut buf, args.dist, args.threads);
+                    unsafe { buf.assume_init() }
+                };
+                let checksum = Checksum::of(&buf[..]);
+
+                let mut buf = std::hint::black_box(buf);
+
+                let start = Instant::now();
+                if let Some(perf) = &mut perf {
+                    perf.enter(Some("sort"));
+                }
+                match self {
+                    Engine::Naive => radix_sort_observed(
+                        &mut buf,
+                        &Identity,
+                        &mut BenchObserver {
+                            perf: None,
+                            progress: args.progress,
+                        },
+                    ),
+                    Engine::Std => buf.sort_unstable(),
+                    _ => buf.sort(),
+                }
+                if let Some(perf) = &mut perf {
+                    perf.enter(None);
+                }
+                let secs = start.elapsed().as_secs_f64();
+
+                let buf = std::hint::black_box(buf);
+                if let Err(err) = verify_sorted(&buf, checksum) {
+                    panic!("{self} did not sort correctly: {err}");
+                }
+                RunResult {
+                    secs,
+                    phase_counts: perf.map_or(vec![], |perf| perf.totals),
+                    stats: None,
+                    pages: None,
+                }
+            }
+        }
+    }
+}
+
+struct RunResult {
+    secs: f64,
+    /// empty unless `--perf` was given
+    phase_counts: PhaseCounts,
+    /// `None` unless `--stats` was given
+    stats: Option<SplitStats>,
+    /// what the slices were on, with `--allocator mmap` or `mmap-huge`
+    pages: Option<Pages>,
+}
+
+impl RunResult {
+    /// Lines describing the performance counters and stats of this run.
+    fn details(&self) -> Vec<String> {
+        let mut res = vec![];
+        for (phase, counts) in &self.phase_counts {
+            let counts = Event::ALL
+                .iter()
+                .zip(counts)
+                .map(|(event, count)| match count {
+                    Some(count) => format!("{count} {}", event.name()),
+                    None => format!("n/a {}", event.name()),
+                })
+                .collect::<Vec<_>>();
+            res.push(format!("{phase}: {}", counts.join(", ")));
+        }
+
+        if let Some(stats) = &self.stats {
+            for (level, level_stats) in stats.levels.iter().enumerate() {
+                res.push(format!(
+                    "L{level}: {:.3} s, {} buckets, {} records",
+                    level_stats.time.as_secs_f64(),
+                    level_stats.buckets_split,
+                    level_stats.records_split,
+                ));
             }
-            Ordering::Equal => (),
-            Ordering::Greater => {
-                incorrect += 1;
-                eprintln!("Failed at {ix}: {key:#x} should be in bucket {current:#x}");
+            res.push(format!(
+                "base case: {:.3} s, {} buckets",
+                stats.base_case_time.as_secs_f64(),
+                stats.base_cases,
+            ));
+            if stats.buckets_sorted_in_place > 0 {
+                res.push(format!(
+                    "over the memory budget: {} buckets sorted in place",
+                    stats.buckets_sorted_in_place
+                ));
+            }
+            res.push(format!(
+                "slices: {} allocated, {} reused, peak {}",
+                stats.slices_allocated,
+                stats.slices_reused,
+                Size(stats.peak_slice_bytes),
+            ));
+        }
+        if let Some(pages) = self.pages {
+            res.push(format!("slices on {pages}"));
+        }
+        res
+    }
+}
+
+/// The total count of each of `Event::ALL`, for each phase of a sort.
+type PhaseCounts = Vec<(&'static str, [Option<u64>; 4])>;
+
+/// Hardware event counts for the current thread, split up by the phase of the sort they happened in.
+struct PhaseCounters {
+    counters: Counters,
+    current: Option<&'static str>,
+    last: [Option<u64>; 4],
+    totals: PhaseCounts,
+}
+
+impl PhaseCounters {
+    /// Start counting, outside of any phase.
+    fn new() -> std::io::Result<Self> {
+        let counters = Counters::new()?;
+        counters.enable();
+        Ok(Self {
+            last: counters.read(),
+            counters,
+            current: None,
+            totals: vec![],
+        })
+    }
+
+    /// Add the events since the last call to the current phase, and move on to `phase`.
+    fn enter(&mut self, phase: Option<&'static str>) {
+        let now = self.counters.read();
+
+        if let Some(current) = self.current {
+            let ix = match self.totals.iter().position(|(name, _)| *name == current) {
+                Some(ix) => ix,
+                None => {
+                    self.totals.push((current, [Some(0); 4]));
+                    self.totals.len() - 1
+                }
+            };
+            for ((total, now), last) in self.totals[ix].1.iter_mut().zip(now).zip(self.last) {
+                *total = match (*total, now, last) {
+                    (Some(total), Some(now), Some(last)) => Some(total + now - last),
+                    _ => None,
+                };
+            }
+        }
+        self.last = now;
+        self.current = phase;
+    }
+}
+
+impl Observer for PhaseCounters {
+    fn on_phase(&mut self, phase: Phase) {
+        self.enter(match phase {
+            Phase::L0 => Some("L0 split"),
+            Phase::Deeper => Some("deeper levels"),
+            Phase::BaseCase => Some("base case"),
+            Phase::Done => None,
+        });
+    }
+}
+
+/// Passes phases on to the performance counters, and draws progress on stderr.
+struct BenchObserver<'p> {
+    perf: Option<&'p mut PhaseCounters>,
+    progress: bool,
+}
+
+impl Observer for BenchObserver<'_> {
+    fn on_progress(&mut self, fraction: f64) {
+        if self.progress {
+            eprint!("\r{:5.1}% ", fraction * 100.0);
+            if fraction >= 1.0 {
+                eprintln!();
             }
         }
     }
 
-    if incorrect != 0 {
-        eprintln!("{incorrect} failures");
+    fn on_phase(&mut self, phase: Phase) {
+        if let Some(perf) = &mut self.perf {
+            perf.on_phase(phase);
+        }
+    }
+}
+
+fn aligned_layout(len: usize) -> Layout {
+    Layout::from_size_align(len * size_of::<u64>(), SLICE_SIZE_BYTES)
+        .expect("SLICE_SIZE_BYTES should be a power of two")
+}
+
+/// Allocate a buffer aligned to `SLICE_SIZE_BYTES`, as `Scheduler::split` needs. Free it with `dealloc_aligned`.
+fn alloc_aligned(len: usize) -> Box<[MaybeUninit<u64>]> {
+    let ptr = unsafe { std::alloc::alloc(aligned_layout(len)) } as *mut MaybeUninit<_>;
+    if ptr.is_null() {
+        std::alloc::handle_alloc_error(aligned_layout(len));
+    }
+    unsafe { Box::from_raw(std::ptr::slice_from_raw_parts_mut(ptr, len)) }
+}
+
+/// # Safety
+///
+/// `buf` must come from `alloc_aligned`.
+unsafe fn dealloc_aligned(buf: Box<[u64]>) {
+    let layout = aligned_layout(buf.len());
+    std::alloc::dealloc(Box::into_raw(buf) as *mut u8, layout);
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Generator {
+    Lcg,
+    Pcg64,
+    Xoshiro256StarStar,
+}
+
+impl FromStr for Generator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lcg" => Ok(Generator::Lcg),
+            "pcg64" => Ok(Generator::Pcg64),
+            "xoshiro256**" | "xoshiro" => Ok(Generator::Xoshiro256StarStar),
+            _ => Err(format!(
+                "unknown generator {s:?}, expected lcg, pcg64 or xoshiro256**"
+            )),
+        }
     }
+}
 
-    if total != BUF_SIZE {
-        eprintln!("Saw {total} elems, expected {BUF_SIZE}");
+impl fmt::Display for Generator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Generator::Lcg => "lcg",
+            Generator::Pcg64 => "pcg64",
+            Generator::Xoshiro256StarStar => "xoshiro256**",
+        })
     }
+}
 
-    incorrect == 0 && total == BUF_SIZE
+impl Generator {
+    fn generate(self, buf: &mut [MaybeUninit<u64>], dist: Distribution, num_threads: usize) {
+        match self {
+            Generator::Lcg => generate(buf, &LCG::new(), dist, num_threads),
+            Generator::Pcg64 => generate(buf, &Pcg64::from_seed(0), dist, num_threads),
+            Generator::Xoshiro256StarStar => {
+                generate(buf, &Xoshiro256StarStar::from_seed(0), dist, num_threads)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Allocator {
+    Global,
+    Arena,
+    Mmap,
+    MmapHuge,
+    Numa,
+}
+
+impl FromStr for Allocator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "global" => Ok(Allocator::Global),
+            "arena" => Ok(Allocator::Arena),
+            "mmap" => Ok(Allocator::Mmap),
+            "mmap-huge" => Ok(Allocator::MmapHuge),
+            "numa" => Ok(Allocator::Numa),
+            _ => Err(format!(
+                "unknown allocator {s:?}, expected global, arena, mmap, mmap-huge or numa"
+            )),
+        }
+    }
+}
+
+impl Allocator {
+    /// An allocator with room for every slice a sort of `args` can use at once, and the pages it is on if it is an
+    /// `MmapArena`.
+    fn create(self, args: &Args) -> (Box<dyn SliceAllocator>, Option<Pages>) {
+        // the input's slices are reused, but each thread can also have a partly filled slice for every bucket of
+        // every level it is splitting
+        let num_slices =
+            args.size / SLICE_SIZE_BYTES + args.threads * MAX_LEVEL_SPLIT as usize * NUM_BUCKETS;
+        let arena = match self {
+            Allocator::Global => return (Box::new(GlobalSlices), None),
+            Allocator::Arena => return (Box::new(Arena::new(num_slices)), None),
+            // any node may have to hold every slice, but only the pages that are touched take memory
+            Allocator::Numa => match NumaSlices::new(num_slices) {
+                Ok(slices) => return (Box::new(slices), None),
+                Err(err) => panic!("could not mmap the slices: {err}"),
+            },
+            Allocator::Mmap => MmapArena::new(num_slices),
+            Allocator::MmapHuge => MmapArena::with_huge_pages(num_slices),
+        };
+        let arena = arena.unwrap_or_else(|err| panic!("could not mmap the slices: {err}"));
+        let pages = arena.pages();
+        (Box::new(arena), Some(pages))
+    }
+}
+
+/// Fill `buf` with keys from `dist`, in parallel. The result does not depend on the number of threads.
+fn generate<G: KeyGenerator>(
+    buf: &mut [MaybeUninit<u64>],
+    gen: &G,
+    dist: Distribution,
+    num_threads: usize,
+) {
+    let len = buf.len();
+    let chunks_per_thread = len.div_ceil(GEN_CHUNK_LEN).div_ceil(num_threads).max(1);
+
+    std::thread::scope(|scope| {
+        for (thread_ix, thread_chunk) in buf
+            .chunks_mut(chunks_per_thread * GEN_CHUNK_LEN)
+            .enumerate()
+        {
+            scope.spawn(move || {
+                for (ix_in_thread, chunk) in thread_chunk.chunks_mut(GEN_CHUNK_LEN).enumerate() {
+                    let chunk_ix = thread_ix * chunks_per_thread + ix_in_thread;
+                    let mut gen = gen.substream(chunk_ix as u64, GEN_CHUNK_LEN as u64);
+                    for (ix, el) in chunk.iter_mut().enumerate() {
+                        el.write(dist.key(chunk_ix * GEN_CHUNK_LEN + ix, len, gen.next_key()));
+                    }
+                }
+            });
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn parse_distributions() {
+        assert_eq!(
+            parse_dist("zipf").unwrap(),
+            Distribution::Zipf {
+                unique: 1 << 20,
+                exponent: 1.0
+            }
+        );
+        assert_eq!(
+            parse_dist("narrow").unwrap(),
+            Distribution::NarrowRange { bits: 20 }
+        );
+        assert_eq!(
+            parse_dist("narrow:8").unwrap(),
+            Distribution::NarrowRange { bits: 8 }
+        );
+        assert!(parse_dist("narrow:65").is_err());
+        assert!(parse_dist("narrow:").is_err());
+        assert!(parse_dist("uniformly").is_err());
+    }
+
+    #[test]
+    fn generate_on_any_number_of_threads() {
+        // a few chunks and a partial one, so that the threads get different numbers of chunks
+        let len = 3 * GEN_CHUNK_LEN + 1000;
+        let dist = Distribution::Uniform;
+        for gen in [
+            Generator::Lcg,
+            Generator::Pcg64,
+            Generator::Xoshiro256StarStar,
+        ] {
+            let mut one = Box::new_uninit_slice(len);
+            gen.generate(&mut one, dist, 1);
+            let one = unsafe { one.assume_init() };
+            for threads in [2, 3, 8] {
+                let mut many = Box::new_uninit_slice(len);
+                gen.generate(&mut many, dist, threads);
+                let many = unsafe { many.assume_init() };
+                assert!(one == many, "{gen} on {threads} threads");
+            }
+        }
+    }
+
+    /// A file in the temp directory, removed when dropped.
+    struct TempFile(String);
+
+    impl TempFile {
+        fn new(name: &str, contents: &str) -> Self {
+            // tests run in parallel, so each file gets a number of its own
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let ix = NEXT.fetch_add(1, Ordering::Relaxed);
+            let path = std::env::temp_dir()
+                .join(format!("pbs-bench-test-{}-{ix}-{name}", std::process::id()));
+            std::fs::write(&path, contents).unwrap();
+            Self(path.to_str().unwrap().to_string())
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn record(engine: &str, run: usize, secs: f64) -> RunRecord {
+        RunRecord {
+            engine: engine.to_string(),
+            dist: "narrow:8".to_string(),
+            gen: "xoshiro256**".to_string(),
+            size: 1 << 30,
+            threads: 8,
+            run,
+            secs,
+        }
+    }
+
+    fn json(records: &[RunRecord]) -> String {
+        records
+            .iter()
+            .map(|record| record.to_json() + "\n")
+            .collect()
+    }
+
+    fn csv(records: &[RunRecord]) -> String {
+        let mut res = RunRecord::FIELDS.join(",") + "\n";
+        for record in records {
+            res += &(record.to_csv() + "\n");
+        }
+        res
+    }
+
+    #[test]
+    fn round_trip() {
+        let records = [record("scheduler", 0, 1.25), record("std", 1, 0.5)];
+        for (name, text) in [("json", json(&records)), ("csv", csv(&records))] {
+            let file = TempFile::new(name, &text);
+            let read = read_records(&file.0).unwrap();
+            // the strings are quoted in both formats, and read back without their quotes
+            assert_eq!(read[0].gen, "xoshiro256**");
+            let read: Vec<String> = read.iter().map(RunRecord::to_json).collect();
+            let expected: Vec<String> = records.iter().map(RunRecord::to_json).collect();
+            assert_eq!(read, expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn json_objects() {
+        let fields = parse_json_object(r#" {"a": "x\"y\\z\u00e9", "b":12.5 ,"c" : -3} "#).unwrap();
+        assert_eq!(fields["a"], "x\"y\\z\u{e9}");
+        assert_eq!(fields["b"], "12.5");
+        assert_eq!(fields["c"], "-3");
+        assert!(parse_json_object("{}").unwrap().is_empty());
+
+        for malformed in [
+            "",
+            "[1]",
+            r#"{"a" 1}"#,
+            r#"{"a": "b"#,
+            r#"{"a": 1"#,
+            r#"{a: 1}"#,
+            r#"{"a\u12": 1}"#,
+        ] {
+            assert!(parse_json_object(malformed).is_err(), "{malformed:?}");
+        }
+    }
+
+    #[test]
+    fn malformed_records() {
+        let missing = TempFile::new("missing", "{\"engine\": \"std\"}\n");
+        assert!(read_records(&missing.0)
+            .unwrap_err()
+            .contains("missing field"));
+        let not_a_number =
+            json(&[record("std", 0, 1.0)]).replace("\"secs\": 1.000000", "\"secs\": \"fast\"");
+        let not_a_number = TempFile::new("nan", &not_a_number);
+        assert!(read_records(&not_a_number.0)
+            .unwrap_err()
+            .contains("not a number"));
+        let bad_csv = TempFile::new("bad-csv", "engine,dist\nstd,uniform\n");
+        assert!(read_records(&bad_csv.0).is_err());
+        assert!(read_records("/nonexistent/results.json").is_err());
+    }
+
+    /// Whether `compare` finds a regression from `old` to `new`, with `args` after the file names.
+    fn regressed(old: &[RunRecord], new: &[RunRecord], args: &[&str]) -> Result<bool, String> {
+        let (old, new) = (
+            TempFile::new("old", &json(old)),
+            TempFile::new("new", &csv(new)),
+        );
+        let paths = [&old.0[..], &new.0[..]];
+        compare(paths.iter().chain(args).map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn compare_threshold() {
+        // the medians are 1.0 s and 1.08 s
+        let old = [
+            record("std", 0, 1.0),
+            record("std", 1, 0.9),
+            record("std", 2, 1.5),
+        ];
+        let new = [
+            record("std", 0, 1.08),
+            record("std", 1, 1.2),
+            record("std", 2, 1.0),
+        ];
+        assert_eq!(regressed(&old, &new, &[]), Ok(true));
+        assert_eq!(regressed(&old, &new, &["--threshold", "10"]), Ok(false));
+        assert_eq!(regressed(&old, &new, &["--threshold=7.5%"]), Ok(true));
+        assert_eq!(regressed(&new, &old, &[]), Ok(false));
+
+        assert!(regressed(&old, &new, &["--threshold"]).is_err());
+        assert!(regressed(&old, &new, &["--threshold=fast"]).is_err());
+        assert!(compare(["only-one.json".to_string()].into_iter()).is_err());
+    }
+
+    #[test]
+    fn compare_configs_in_one_file() {
+        // configurations that are only in one of the files are listed, but are not regressions
+        let old = [record("std", 0, 1.0), record("naive", 0, 1.0)];
+        let new = [record("std", 0, 1.0), record("scheduler", 0, 100.0)];
+        assert_eq!(regressed(&old, &new, &[]), Ok(false));
+        assert_eq!(regressed(&old, &[], &[]), Ok(false));
+    }
 }
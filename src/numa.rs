@@ -0,0 +1,276 @@
+//! Which CPUs are on which NUMA node, pinning threads to CPUs, and binding memory to a node, on Linux.
+//!
+//! Elsewhere (or where sysfs does not tell), every CPU counts as one node, pinning fails and binding does nothing,
+//! so code that uses this works the same on a single-node machine.
+
+use std::fs;
+use std::io;
+
+/// The NUMA nodes of this machine, and their CPUs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Topology {
+    /// the CPUs of each node, in order. Every node has at least one CPU.
+    nodes: Vec<Vec<usize>>,
+}
+
+impl Topology {
+    /// Read the topology from `/sys/devices/system/node`, or fall back to `single_node`.
+    ///
+    /// Nodes without CPUs (such as memory-only nodes) are left out.
+    pub fn detect() -> Self {
+        Self::from_sysfs().unwrap_or_else(Self::single_node)
+    }
+
+    /// One node, with as many CPUs as `std::thread::available_parallelism`.
+    pub fn single_node() -> Self {
+        let num_cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+        Self {
+            nodes: vec![(0..num_cpus).collect()],
+        }
+    }
+
+    fn from_sysfs() -> Option<Self> {
+        let online = fs::read_to_string("/sys/devices/system/node/online").ok()?;
+        let nodes = parse_cpu_list(&online)?
+            .into_iter()
+            .filter_map(|node| {
+                let cpus =
+                    fs::read_to_string(format!("/sys/devices/system/node/node{node}/cpulist"))
+                        .ok()?;
+                parse_cpu_list(&cpus).filter(|cpus| !cpus.is_empty())
+            })
+            .collect::<Vec<_>>();
+        (!nodes.is_empty()).then_some(Self { nodes })
+    }
+
+    pub fn num_nodes(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// The CPUs of `node`, an index below `num_nodes`.
+    pub fn cpus(&self, node: usize) -> &[usize] {
+        &self.nodes[node]
+    }
+
+    /// The node that `cpu` is on, if it is one of ours.
+    pub fn node_of(&self, cpu: usize) -> Option<usize> {
+        self.nodes.iter().position(|cpus| cpus.contains(&cpu))
+    }
+
+    /// The node of the CPU that the calling thread is running on, or 0 if we cannot tell.
+    pub fn current_node(&self) -> usize {
+        sys::current_cpu()
+            .and_then(|cpu| self.node_of(cpu))
+            .unwrap_or(0)
+    }
+
+    /// A CPU for each of `num_threads` threads, taking one CPU from each node in turn, so that the threads are
+    /// spread evenly over the nodes. Once every CPU is taken, they are handed out again in the same order.
+    pub fn spread(&self, num_threads: usize) -> Vec<usize> {
+        let max_cpus = self.nodes.iter().map(Vec::len).max().unwrap_or(0);
+        let order = (0..max_cpus)
+            .flat_map(|ix| {
+                self.nodes
+                    .iter()
+                    .filter_map(move |cpus| cpus.get(ix).copied())
+            })
+            .collect::<Vec<_>>();
+        order.iter().copied().cycle().take(num_threads).collect()
+    }
+}
+
+/// Parse a list like "0-3,8,10-11", as in sysfs.
+fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+    let mut res = vec![];
+    for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+        match range.split_once('-') {
+            Some((first, last)) => res.extend(first.parse::<usize>().ok()?..=last.parse().ok()?),
+            None => res.push(range.parse().ok()?),
+        }
+    }
+    Some(res)
+}
+
+/// Run the calling thread only on `cpu` from now on.
+pub fn pin_current_thread(cpu: usize) -> io::Result<()> {
+    sys::set_affinity(cpu)
+}
+
+/// Ask the kernel to put the pages of `len` bytes at `ptr` on `node`, preferably, when they are first touched.
+///
+/// Does nothing where there is no NUMA support, since then there is only one place for the pages to go.
+///
+/// # Safety
+///
+/// `ptr` must be page-aligned, and the range must be mapped.
+pub(crate) unsafe fn prefer_node(ptr: *mut u8, len: usize, node: usize) -> io::Result<()> {
+    match sys::mbind_preferred(ptr, len, node) {
+        Err(err) if err.raw_os_error() == Some(sys::ENOSYS) => Ok(()),
+        res => res,
+    }
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod sys {
+    use std::io;
+    use std::os::raw::{c_int, c_long, c_ulong, c_void};
+
+    pub const ENOSYS: i32 = 38;
+
+    #[cfg(target_arch = "x86_64")]
+    const SYS_MBIND: c_long = 237;
+    #[cfg(target_arch = "aarch64")]
+    const SYS_MBIND: c_long = 235;
+    const MPOL_PREFERRED: c_int = 1;
+
+    // the kernel's cpu_set_t, big enough for 1024 CPUs
+    const CPU_SET_WORDS: usize = 1024 / c_ulong::BITS as usize;
+
+    extern "C" {
+        fn syscall(num: c_long, ...) -> c_long;
+        fn sched_setaffinity(pid: c_int, size: usize, mask: *const c_ulong) -> c_int;
+        fn sched_getcpu() -> c_int;
+    }
+
+    pub fn set_affinity(cpu: usize) -> io::Result<()> {
+        let bits = c_ulong::BITS as usize;
+        if cpu >= CPU_SET_WORDS * bits {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        let mut mask = [0 as c_ulong; CPU_SET_WORDS];
+        mask[cpu / bits] |= 1 << (cpu % bits);
+        // pid 0 is the calling thread
+        if unsafe { sched_setaffinity(0, size_of_val(&mask), mask.as_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn current_cpu() -> Option<usize> {
+        usize::try_from(unsafe { sched_getcpu() }).ok()
+    }
+
+    pub unsafe fn mbind_preferred(ptr: *mut u8, len: usize, node: usize) -> io::Result<()> {
+        let bits = c_ulong::BITS as usize;
+        let mut nodemask = vec![0 as c_ulong; node / bits + 1];
+        nodemask[node / bits] |= 1 << (node % bits);
+        // maxnode is the number of bits in the mask
+        let max_node = (nodemask.len() * bits) as c_ulong;
+        let flags: c_ulong = 0;
+        let res = syscall(
+            SYS_MBIND,
+            ptr as *mut c_void,
+            len as c_ulong,
+            MPOL_PREFERRED,
+            nodemask.as_ptr(),
+            max_node,
+            flags,
+        );
+        if res != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+mod sys {
+    use std::io;
+
+    // never returned, since nothing here makes a system call
+    pub const ENOSYS: i32 = -1;
+
+    pub fn set_affinity(_cpu: usize) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub fn current_cpu() -> Option<usize> {
+        None
+    }
+
+    pub unsafe fn mbind_preferred(_ptr: *mut u8, _len: usize, _node: usize) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpu_lists() {
+        assert_eq!(
+            parse_cpu_list("0-3,8,10-11\n"),
+            Some(vec![0, 1, 2, 3, 8, 10, 11])
+        );
+        assert_eq!(parse_cpu_list("5"), Some(vec![5]));
+        assert_eq!(parse_cpu_list("2,4-4"), Some(vec![2, 4]));
+        // a node without CPUs has an empty list
+        assert_eq!(parse_cpu_list("\n"), Some(vec![]));
+
+        for malformed in ["x", "0-", "-3", "1-2-3", "0,a", "1 2"] {
+            assert_eq!(parse_cpu_list(malformed), None, "{malformed:?}");
+        }
+    }
+
+    #[test]
+    fn single_node() {
+        let topology = Topology::single_node();
+        let num_cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+        assert_eq!(topology.num_nodes(), 1);
+        assert_eq!(topology.cpus(0), (0..num_cpus).collect::<Vec<_>>());
+        assert_eq!(topology.node_of(0), Some(0));
+        assert_eq!(topology.node_of(num_cpus), None);
+        assert_eq!(topology.current_node(), 0);
+        assert_eq!(
+            topology.spread(num_cpus + 1),
+            (0..num_cpus).chain([0]).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn spread() {
+        let topology = Topology {
+            nodes: vec![vec![0, 1, 2], vec![4, 5]],
+        };
+        assert_eq!(topology.node_of(5), Some(1));
+        assert_eq!(topology.node_of(3), None);
+        assert_eq!(topology.spread(0), Vec::<usize>::new());
+        assert_eq!(topology.spread(3), [0, 4, 1]);
+        // once every CPU is taken, in the same order again
+        assert_eq!(topology.spread(7), [0, 4, 1, 5, 2, 0, 4]);
+    }
+
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    #[test]
+    fn numa_slices_on_one_node() {
+        use crate::allocators::{NumaSlices, SliceAllocator};
+        use crate::scheduler::SLICE_SIZE_BYTES;
+
+        let slices = NumaSlices::with_topology(Topology::single_node(), 4).unwrap();
+        let taken: Vec<_> = std::iter::from_fn(|| slices.allocate()).collect();
+        // an extra slice for alignment may be left over
+        assert!((4..=5).contains(&taken.len()), "{} slices", taken.len());
+        for slice in &taken {
+            assert!(slice.as_ptr().is_aligned_to(SLICE_SIZE_BYTES));
+            unsafe { slice.as_ptr().write_bytes(0x5A, SLICE_SIZE_BYTES) };
+        }
+
+        for &slice in &taken {
+            unsafe { slices.deallocate(slice) };
+        }
+        assert_eq!(
+            std::iter::from_fn(|| slices.allocate()).count(),
+            taken.len()
+        );
+    }
+}
@@ -0,0 +1,59 @@
+/// The parts of `Scheduler::split` that can be measured separately, see `Observer::on_phase`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// splitting the input on the first byte of the key
+    L0,
+    /// splitting buckets on the following bytes
+    Deeper,
+    /// sorting buckets of a single slice with `Splitter::split_small`
+    BaseCase,
+    /// the sort is finished
+    Done,
+}
+
+/// Hooks into the progress of a sort, e.g. to draw a progress bar or to log. Every method does nothing by default.
+///
+/// Level `n` splits buckets on byte `n` of the key, so the L0 split is level 0.
+pub trait Observer {
+    /// The first bucket at `level` is about to be split.
+    ///
+    /// Buckets are split depth first, so this is not the end of the previous level.
+    fn on_level_start(&mut self, _level: usize) {}
+
+    /// A bucket of `len` records at `level` has been split. `bucket_id` holds the key bytes its records share, as
+    /// far as they are in the first word of the key.
+    fn on_bucket_split(&mut self, _level: usize, _bucket_id: u64, _len: usize) {}
+
+    /// `fraction` of the records are in their final place.
+    fn on_progress(&mut self, _fraction: f64) {}
+
+    /// The sort moved on to `phase`, e.g. to read performance counters at phase boundaries.
+    ///
+    /// Only the scheduler reports phases, and in `Scheduler::split_parallel`, only those of the thread it was called
+    /// on: `L0` and then `Done`.
+    fn on_phase(&mut self, _phase: Phase) {}
+}
+
+/// Ignores everything.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoObserver;
+
+impl Observer for NoObserver {}
+
+impl<O: Observer + ?Sized> Observer for &mut O {
+    fn on_level_start(&mut self, level: usize) {
+        (**self).on_level_start(level)
+    }
+
+    fn on_bucket_split(&mut self, level: usize, bucket_id: u64, len: usize) {
+        (**self).on_bucket_split(level, bucket_id, len)
+    }
+
+    fn on_progress(&mut self, fraction: f64) {
+        (**self).on_progress(fraction)
+    }
+
+    fn on_phase(&mut self, phase: Phase) {
+        (**self).on_phase(phase)
+    }
+}
@@ -0,0 +1,196 @@
+//! Hardware performance counters for the calling thread, through Linux's `perf_event_open`.
+//!
+//! On other platforms, `Counters::new` always fails.
+
+use std::io;
+use std::os::raw::{c_int, c_ulong, c_void};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    Instructions,
+    CacheMisses,
+    BranchMisses,
+    DtlbMisses,
+}
+
+impl Event {
+    pub const ALL: [Event; 4] = [
+        Event::Instructions,
+        Event::CacheMisses,
+        Event::BranchMisses,
+        Event::DtlbMisses,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Event::Instructions => "instructions",
+            Event::CacheMisses => "cache misses",
+            Event::BranchMisses => "branch misses",
+            Event::DtlbMisses => "dTLB misses",
+        }
+    }
+
+    /// The `type` and `config` of its `perf_event_attr`.
+    fn type_and_config(self) -> (u32, u64) {
+        const PERF_TYPE_HARDWARE: u32 = 0;
+        const PERF_TYPE_HW_CACHE: u32 = 3;
+        match self {
+            Event::Instructions => (PERF_TYPE_HARDWARE, 1),
+            Event::CacheMisses => (PERF_TYPE_HARDWARE, 3),
+            Event::BranchMisses => (PERF_TYPE_HARDWARE, 5),
+            // cache DTLB (3), op READ (0), result MISS (1)
+            Event::DtlbMisses => (PERF_TYPE_HW_CACHE, 3 | (1 << 16)),
+        }
+    }
+}
+
+/// The first fields of the kernel's `struct perf_event_attr`, padded to the size of version 7 of it.
+#[repr(C)]
+struct PerfEventAttr {
+    type_: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    rest: [u64; 10],
+}
+
+const FLAG_DISABLED: u64 = 1 << 0;
+const FLAG_EXCLUDE_KERNEL: u64 = 1 << 5;
+const FLAG_EXCLUDE_HV: u64 = 1 << 6;
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod sys {
+    use std::os::raw::{c_int, c_long, c_ulong, c_void};
+
+    pub const SUPPORTED: bool = true;
+
+    #[cfg(target_arch = "x86_64")]
+    const SYS_PERF_EVENT_OPEN: c_long = 298;
+    #[cfg(target_arch = "aarch64")]
+    const SYS_PERF_EVENT_OPEN: c_long = 241;
+
+    extern "C" {
+        fn syscall(num: c_long, ...) -> c_long;
+        pub fn ioctl(fd: c_int, request: c_ulong, ...) -> c_int;
+        pub fn read(fd: c_int, buf: *mut c_void, count: usize) -> isize;
+        pub fn close(fd: c_int) -> c_int;
+    }
+
+    pub unsafe fn perf_event_open(attr: *const super::PerfEventAttr) -> c_int {
+        // this thread, on any CPU, in no group
+        let (pid, cpu, group_fd): (c_int, c_int, c_int) = (0, -1, -1);
+        syscall(SYS_PERF_EVENT_OPEN, attr, pid, cpu, group_fd, 0 as c_ulong) as c_int
+    }
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+mod sys {
+    // no counter is ever opened, so nothing but `SUPPORTED` is used
+    use std::os::raw::{c_int, c_ulong, c_void};
+
+    pub const SUPPORTED: bool = false;
+
+    pub unsafe fn perf_event_open(_attr: *const super::PerfEventAttr) -> c_int {
+        -1
+    }
+    pub unsafe fn ioctl(_fd: c_int, _request: c_ulong, _arg: c_ulong) -> c_int {
+        -1
+    }
+    pub unsafe fn read(_fd: c_int, _buf: *mut c_void, _count: usize) -> isize {
+        -1
+    }
+    pub unsafe fn close(_fd: c_int) -> c_int {
+        -1
+    }
+}
+
+const PERF_EVENT_IOC_ENABLE: c_ulong = 0x2400;
+const PERF_EVENT_IOC_DISABLE: c_ulong = 0x2401;
+const PERF_EVENT_IOC_RESET: c_ulong = 0x2403;
+
+/// One counter for each of `Event::ALL`, counting user-space events of the thread that created them.
+///
+/// The counters start disabled. Events that this machine (or this container) cannot count read as `None`.
+pub struct Counters {
+    fds: [Option<c_int>; 4],
+}
+
+impl Counters {
+    /// Fails if none of the events can be counted, e.g. because `perf_event_paranoid` forbids it.
+    pub fn new() -> io::Result<Self> {
+        if !sys::SUPPORTED {
+            return Err(io::ErrorKind::Unsupported.into());
+        }
+
+        let mut last_err = None;
+        let fds = Event::ALL.map(|event| {
+            let (type_, config) = event.type_and_config();
+            let attr = PerfEventAttr {
+                type_,
+                size: std::mem::size_of::<PerfEventAttr>() as u32,
+                config,
+                sample_period: 0,
+                sample_type: 0,
+                read_format: 0,
+                flags: FLAG_DISABLED | FLAG_EXCLUDE_KERNEL | FLAG_EXCLUDE_HV,
+                rest: [0; 10],
+            };
+            let fd = unsafe { sys::perf_event_open(&attr) };
+            if fd < 0 {
+                last_err = Some(io::Error::last_os_error());
+                None
+            } else {
+                Some(fd)
+            }
+        });
+
+        match last_err {
+            Some(err) if fds.iter().all(Option::is_none) => Err(err),
+            _ => Ok(Self { fds }),
+        }
+    }
+
+    fn ioctl_all(&self, request: c_ulong) {
+        for fd in self.fds.iter().flatten() {
+            unsafe { sys::ioctl(*fd, request, 0 as c_ulong) };
+        }
+    }
+
+    pub fn enable(&self) {
+        self.ioctl_all(PERF_EVENT_IOC_ENABLE);
+    }
+
+    pub fn disable(&self) {
+        self.ioctl_all(PERF_EVENT_IOC_DISABLE);
+    }
+
+    pub fn reset(&self) {
+        self.ioctl_all(PERF_EVENT_IOC_RESET);
+    }
+
+    /// The current value of each counter, in the order of `Event::ALL`.
+    pub fn read(&self) -> [Option<u64>; 4] {
+        self.fds.map(|fd| {
+            let mut value = 0u64;
+            let len = unsafe { sys::read(fd?, &mut value as *mut u64 as *mut c_void, 8) };
+            (len == 8).then_some(value)
+        })
+    }
+}
+
+impl Drop for Counters {
+    fn drop(&mut self) {
+        for fd in self.fds.iter().flatten() {
+            unsafe { sys::close(*fd) };
+        }
+    }
+}
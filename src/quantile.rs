@@ -0,0 +1,207 @@
+use crate::scheduler::{Scheduler, MAX_LEVEL_SPLIT, NUM_BUCKETS};
+use crate::splitters::ScalarSplitter;
+
+/// Bounds on a quantile, computed from the histograms of the top levels of the split tree only.
+///
+/// The true quantile always lies in `lower..=upper`, so `upper - lower` bounds the error of `estimate`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QuantileBounds {
+    pub lower: u64,
+    pub upper: u64,
+    /// Linear interpolation of the rank within its bucket.
+    pub estimate: u64,
+}
+
+/// The (nearest-rank) index of quantile `q` in a sorted array of `len` keys.
+pub fn rank_of_quantile(len: usize, q: f64) -> usize {
+    assert!((0.0..=1.0).contains(&q), "quantile {q} is not in [0, 1]");
+    assert!(len > 0, "cannot take a quantile of no keys");
+    ((q * len as f64).ceil() as usize).clamp(1, len) - 1
+}
+
+/// The exact quantile `q` (e.g. 0.99 for p99) of `input`, or `None` if `input` is empty.
+pub fn quantile(input: &[u64], q: f64) -> Option<u64> {
+    quantiles(input, &[q]).pop()
+}
+
+/// The exact quantiles `qs` of `input`, in the same order as `qs`. Empty if `input` is empty.
+pub fn quantiles(input: &[u64], qs: &[f64]) -> Vec<u64> {
+    if input.is_empty() {
+        return vec![];
+    }
+    let ranks: Vec<usize> = qs
+        .iter()
+        .map(|&q| rank_of_quantile(input.len(), q))
+        .collect();
+    select_many(input, &ranks)
+}
+
+/// The key that would be at index `rank` if `input` were sorted.
+pub fn select(input: &[u64], rank: usize) -> u64 {
+    select_many(input, &[rank])[0]
+}
+
+/// The keys that would be at each of `ranks` if `input` were sorted, in the same order as `ranks`.
+///
+/// `input` is split into the slices of a `Scheduler` like the first level of a sort, but after that only the
+/// buckets containing one of `ranks` are split further, so this does much less work than sorting.
+pub fn select_many(input: &[u64], ranks: &[usize]) -> Vec<u64> {
+    // (rank, index into the result), sorted by rank so we can match them against buckets in order
+    let mut order: Vec<(usize, usize)> = ranks
+        .iter()
+        .enumerate()
+        .map(|(ix, &rank)| (rank, ix))
+        .collect();
+    order.sort_unstable();
+
+    if let Some(&(rank, _)) = order.last() {
+        assert!(
+            rank < input.len(),
+            "rank {rank} out of bounds for {} keys",
+            input.len()
+        );
+    }
+
+    let mut res = vec![0; ranks.len()];
+    if !order.is_empty() {
+        Scheduler::new().select(input, &order, &mut res, &mut ScalarSplitter::new());
+    }
+    res
+}
+
+/// Approximate quantiles `qs` of `input`, looking only at the top `levels` bytes of each key.
+///
+/// Each level is one read-only pass over `input` that histograms the next byte of the keys sharing one of the
+/// prefixes found so far, with a histogram for each distinct prefix; no keys are copied. With `levels == 1` only
+/// the L0 histogram is built, with `levels == 2` the L0 and L1 histograms, and so on; `levels == 8` gives exact
+/// results. Empty if `input` is empty.
+pub fn approx_quantiles(input: &[u64], qs: &[f64], levels: u8) -> Vec<QuantileBounds> {
+    assert!(
+        (1..=MAX_LEVEL_SPLIT).contains(&levels),
+        "levels must be between 1 and {MAX_LEVEL_SPLIT}"
+    );
+    if input.is_empty() {
+        return vec![];
+    }
+
+    // for each quantile: the prefix of the bucket holding it, and its rank within that bucket
+    let mut prefixes = vec![0u64; qs.len()];
+    let mut ranks: Vec<usize> = qs
+        .iter()
+        .map(|&q| rank_of_quantile(input.len(), q))
+        .collect();
+    let mut bucket_lens = vec![input.len(); qs.len()];
+
+    for level in 0..levels {
+        let prefix_shift = 64 - level as u32 * 8;
+        let shift = prefix_shift - 8;
+
+        // quantiles close together often share a prefix, and so a histogram
+        let mut wanted = prefixes.clone();
+        wanted.sort_unstable();
+        wanted.dedup();
+        let mut counts = vec![[0usize; NUM_BUCKETS]; wanted.len()];
+        for &key in input {
+            let prefix = key.checked_shr(prefix_shift).unwrap_or(0);
+            if let Ok(slot) = wanted.binary_search(&prefix) {
+                counts[slot][((key >> shift) & 0xFF) as usize] += 1;
+            }
+        }
+
+        for (prefix, (rank, bucket_len)) in prefixes
+            .iter_mut()
+            .zip(ranks.iter_mut().zip(bucket_lens.iter_mut()))
+        {
+            let counts = &counts[wanted.binary_search(prefix).unwrap()];
+            let mut buck = 0;
+            while *rank >= counts[buck] {
+                *rank -= counts[buck];
+                buck += 1;
+            }
+            *prefix = (*prefix << 8) | buck as u64;
+            *bucket_len = counts[buck];
+        }
+    }
+
+    let low_bits = 64 - levels as u32 * 8;
+    prefixes
+        .into_iter()
+        .zip(ranks.into_iter().zip(bucket_lens))
+        .map(|(prefix, (rank, bucket_len))| {
+            let lower = prefix << low_bits;
+            let upper = lower | ((1 << low_bits) - 1);
+            let offset = (upper - lower) as u128 * rank as u128 / bucket_len as u128;
+            QuantileBounds {
+                lower,
+                upper,
+                estimate: lower + offset as u64,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::SLICE_SIZE;
+    use crate::workloads::test_inputs;
+
+    const LEN: usize = 2 * SLICE_SIZE + 321;
+    const QS: [f64; 7] = [0.0, 0.01, 0.25, 0.5, 0.5, 0.99, 1.0];
+
+    fn sorted(keys: &[u64]) -> Vec<u64> {
+        let mut sorted = keys.to_vec();
+        sorted.sort_unstable();
+        sorted
+    }
+
+    #[test]
+    fn exact_quantiles() {
+        for (dist, keys) in test_inputs(LEN) {
+            let sorted = sorted(&keys);
+            let expected: Vec<u64> = QS
+                .iter()
+                .map(|&q| sorted[rank_of_quantile(LEN, q)])
+                .collect();
+            assert_eq!(quantiles(&keys, &QS), expected, "{dist:?}");
+            assert_eq!(
+                quantile(&keys, 0.9),
+                Some(sorted[rank_of_quantile(LEN, 0.9)])
+            );
+        }
+        assert_eq!(quantile(&[], 0.5), None);
+    }
+
+    #[test]
+    fn select_unordered_ranks() {
+        for (dist, keys) in test_inputs(LEN) {
+            let sorted = sorted(&keys);
+            let ranks = [LEN - 1, 0, 5000, 5000, SLICE_SIZE, 1, LEN / 2];
+            let expected: Vec<u64> = ranks.iter().map(|&rank| sorted[rank]).collect();
+            assert_eq!(select_many(&keys, &ranks), expected, "{dist:?}");
+            assert_eq!(select(&keys, 17), sorted[17], "{dist:?}");
+        }
+        assert!(select_many(&[3, 1, 2], &[]).is_empty());
+    }
+
+    #[test]
+    fn approx_quantiles_bound_the_exact_ones() {
+        for (dist, keys) in test_inputs(LEN) {
+            let sorted = sorted(&keys);
+            for levels in 1..=MAX_LEVEL_SPLIT {
+                let bounds = approx_quantiles(&keys, &QS, levels);
+                for (&q, bounds) in QS.iter().zip(bounds) {
+                    let exact = sorted[rank_of_quantile(LEN, q)];
+                    assert!(
+                        bounds.lower <= exact && exact <= bounds.upper,
+                        "{dist:?}, {levels} levels: {exact} not in {bounds:?}"
+                    );
+                    assert!((bounds.lower..=bounds.upper).contains(&bounds.estimate));
+                    if levels == MAX_LEVEL_SPLIT {
+                        assert_eq!(bounds.estimate, exact, "{dist:?}");
+                    }
+                }
+            }
+        }
+    }
+}
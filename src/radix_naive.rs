@@ -1,7 +1,24 @@
+use crate::observer::{NoObserver, Observer};
+use crate::transforms::{Identity, KeyTransform};
+
 /// Goal: we should be able to replace Vec with our Slice type, passing in a SliceMgr, and have everything "just work"
 
 /// A naive radix sort, using resizing Vectors.
 pub fn radix_sort(input: &mut Box<[u64]>) {
+    radix_sort_by(input, &Identity)
+}
+
+/// A naive radix sort, ordering keys by `transform(key)`.
+pub fn radix_sort_by<T: KeyTransform>(input: &mut Box<[u64]>, transform: &T) {
+    radix_sort_observed(input, transform, &mut NoObserver)
+}
+
+/// Like `radix_sort_by`, reporting progress to `observer`.
+pub fn radix_sort_observed<T: KeyTransform>(
+    input: &mut Box<[u64]>,
+    transform: &T,
+    observer: &mut dyn Observer,
+) {
     // TODO: use lens explicitly? Currently each stackframe contains its own len array
     // let mut lens: [[usize; 256]; 8] = [[0; 256]; 8];
     const EMPTY_BUCKET: Vec<u64> = Vec::new();
@@ -9,12 +26,17 @@ pub fn radix_sort(input: &mut Box<[u64]>) {
     let input_len = input.len();
 
     // L0 split
+    observer.on_level_start(0);
     for &key in input.iter() {
-        let buck = (key >> 56) as usize;
+        let buck = (transform.transform(key) >> 56) as usize;
         buckets[buck].push(key);
     }
+    observer.on_bucket_split(0, 0, input_len);
 
-    eprintln!("Finished L0");
+    let mut tracker = Tracker {
+        observer,
+        levels_started: 1,
+    };
 
     // we've already seen and copied all the keys from input, so we can reuse this memory
     let mut output = {
@@ -30,7 +52,18 @@ pub fn radix_sort(input: &mut Box<[u64]>) {
     for buck in 0..256 {
         let mut input = Vec::new();
         std::mem::swap(&mut buckets[buck], &mut input);
-        radix_sort_helper(&input, &mut buckets, &mut output, 2, (buck as u64) << 56);
+        radix_sort_helper(
+            &input,
+            &mut buckets,
+            &mut output,
+            transform,
+            2,
+            (buck as u64) << 56,
+            &mut tracker,
+        );
+        tracker
+            .observer
+            .on_progress(output.len() as f64 / input_len as f64);
     }
 
     // lens[0] = buckets.map(|bucket| bucket.len());
@@ -42,29 +75,33 @@ pub fn radix_sort(input: &mut Box<[u64]>) {
     std::mem::swap(&mut output.into_boxed_slice(), input);
 }
 
-fn radix_sort_helper(
+/// Passes events on to the observer, reporting each level only once.
+struct Tracker<'o> {
+    observer: &'o mut dyn Observer,
+    levels_started: usize,
+}
+
+fn radix_sort_helper<T: KeyTransform>(
     input: &[u64],
     buckets: &mut [Vec<u64>; 256],
     output: &mut Vec<u64>,
+    transform: &T,
     level: u8,
     bucket_id: u64,
+    tracker: &mut Tracker,
 ) {
-    // eprint!("\r{bucket_id:#018x}, Splitting L{level}");
-
     if input.len() <= 32 {
         // small array, base case
-        // eprint!("; finishing {} elements", input.len());
         let start_ix = output.len();
         output.extend(input);
         // FIXME use sorting networks - preferably offloading sorting networks
-        output[start_ix..].sort();
+        output[start_ix..].sort_by_key(|&key| transform.transform(key));
         debug_assert_eq!(start_ix + input.len(), output.len());
         return;
     }
 
     if level == 9 {
         // all keys are the same!
-        // eprint!("; finishing");
         output.extend(input);
         return;
     }
@@ -72,6 +109,13 @@ fn radix_sort_helper(
     let shift = (8 - level) * 8;
     let mask = 0xFF;
 
+    // the observer counts levels from 0 for the L0 split
+    let observer_level = level as usize - 1;
+    if observer_level >= tracker.levels_started {
+        tracker.levels_started = observer_level + 1;
+        tracker.observer.on_level_start(observer_level);
+    }
+
     // save these to reset the ends of buckets
     let bucket_lens: [usize; 256] = buckets
         .iter()
@@ -83,7 +127,7 @@ fn radix_sort_helper(
 
     // Split these buckets
     for &key in input {
-        let buck = ((key >> shift) & mask) as usize;
+        let buck = ((transform.transform(key) >> shift) & mask) as usize;
         buckets[buck].push(key);
     }
 
@@ -95,6 +139,9 @@ fn radix_sort_helper(
             .sum::<usize>(),
         input.len()
     );
+    tracker
+        .observer
+        .on_bucket_split(observer_level, bucket_id, input.len());
 
     let output_len_before = output.len();
 
@@ -115,7 +162,15 @@ fn radix_sort_helper(
         // We can place this into buckets so we don't have to deallocate this one and allocate a new one.
         debug_assert!(saved_bucket.is_empty());
         std::mem::swap(&mut buckets[buck], &mut saved_bucket);
-        radix_sort_helper(&saved_bucket[bucket_lens[buck]..], buckets, output, level + 1, bucket_id);
+        radix_sort_helper(
+            &saved_bucket[bucket_lens[buck]..],
+            buckets,
+            output,
+            transform,
+            level + 1,
+            bucket_id,
+            tracker,
+        );
         std::mem::swap(&mut buckets[buck], &mut saved_bucket);
 
         // TODO can maybe replace with Vec::set_len?
@@ -0,0 +1,263 @@
+use crate::transforms::{FloatOrder, KeyTransform};
+
+/// Something that can be sorted by a `u64` key. Records with equal keys need not be identical.
+///
+/// Longer keys are made of several words, compared most significant first.
+pub trait Record: Copy {
+    const KEY_WORDS: usize = 1;
+    /// How many bytes of the key, from the most significant, can differ between records. Sorts stop splitting after
+    /// this many levels, since the rest of the key is the same in every record.
+    const KEY_BYTES: usize = 8 * Self::KEY_WORDS;
+
+    fn key(&self) -> u64;
+
+    /// Word `word` of the key, for `word < KEY_WORDS`. The first word is `key()`.
+    fn key_word(&self, word: usize) -> u64 {
+        debug_assert_eq!(word, 0);
+        self.key()
+    }
+}
+
+impl Record for u64 {
+    #[inline(always)]
+    fn key(&self) -> u64 {
+        *self
+    }
+}
+
+/// The key holds the value in its top half, so that the first levels split on its bytes.
+impl Record for u32 {
+    const KEY_BYTES: usize = 4;
+
+    #[inline(always)]
+    fn key(&self) -> u64 {
+        (*self as u64) << 32
+    }
+}
+
+/// A key with an arbitrary payload.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KeyValue<V> {
+    pub key: u64,
+    pub value: V,
+}
+
+impl<V: Copy> Record for KeyValue<V> {
+    #[inline(always)]
+    fn key(&self) -> u64 {
+        self.key
+    }
+}
+
+/// A typed column of a `Composite` key.
+pub trait KeyColumn: Copy {
+    /// The width of this column in the concatenated key.
+    const BYTES: usize;
+
+    /// The low `BYTES` bytes of the result are ordered as unsigned integers like `self` is.
+    fn order_bits(&self) -> u64;
+}
+
+/// Sort this column in descending order.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Desc<T>(pub T);
+
+/// A record sorted by several columns, e.g. `Composite<(u32, i64, f64)>`.
+///
+/// The key is the concatenation of each column's order-preserving bytes, so a composite key can be longer than
+/// one word.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Composite<T>(pub T);
+
+macro_rules! impl_unsigned_column {
+    ($($ty:ty),*) => {$(
+        impl KeyColumn for $ty {
+            const BYTES: usize = std::mem::size_of::<$ty>();
+
+            #[inline(always)]
+            fn order_bits(&self) -> u64 {
+                *self as u64
+            }
+        }
+    )*};
+}
+
+macro_rules! impl_signed_column {
+    ($($ty:ty => $unsigned:ty),*) => {$(
+        impl KeyColumn for $ty {
+            const BYTES: usize = std::mem::size_of::<$ty>();
+
+            #[inline(always)]
+            fn order_bits(&self) -> u64 {
+                ((*self as $unsigned) ^ (1 << (<$unsigned>::BITS - 1))) as u64
+            }
+        }
+    )*};
+}
+
+impl_unsigned_column!(u8, u16, u32, u64);
+impl_signed_column!(i8 => u8, i16 => u16, i32 => u32, i64 => u64);
+
+impl KeyColumn for f32 {
+    const BYTES: usize = 4;
+
+    #[inline(always)]
+    fn order_bits(&self) -> u64 {
+        FloatOrder.transform((self.to_bits() as u64) << 32) >> 32
+    }
+}
+
+impl KeyColumn for f64 {
+    const BYTES: usize = 8;
+
+    #[inline(always)]
+    fn order_bits(&self) -> u64 {
+        FloatOrder.transform(self.to_bits())
+    }
+}
+
+impl<T: KeyColumn> KeyColumn for Desc<T> {
+    const BYTES: usize = T::BYTES;
+
+    #[inline(always)]
+    fn order_bits(&self) -> u64 {
+        !self.0.order_bits() & (!0 >> (64 - 8 * Self::BYTES))
+    }
+}
+
+/// The part of a column that lands in word `word` of the concatenated key, when the column starts `offset` bytes
+/// into the key.
+#[inline(always)]
+fn column_in_word(bits: u64, bytes: usize, offset: usize, word: usize) -> u64 {
+    // how many bytes the end of the column is before the end of this word
+    let shift = (8 * word + 8) as isize - (offset + bytes) as isize;
+    if shift >= 0 {
+        bits.checked_shl(shift as u32 * 8).unwrap_or(0)
+    } else {
+        bits.checked_shr(-shift as u32 * 8).unwrap_or(0)
+    }
+}
+
+macro_rules! impl_composite {
+    ($($col:ident: $ix:tt),+) => {
+        impl<$($col: KeyColumn),+> Record for Composite<($($col,)+)> {
+            const KEY_WORDS: usize = Self::KEY_BYTES.div_ceil(8);
+            const KEY_BYTES: usize = 0 $(+ $col::BYTES)+;
+
+            #[inline(always)]
+            fn key(&self) -> u64 {
+                self.key_word(0)
+            }
+
+            #[inline(always)]
+            fn key_word(&self, word: usize) -> u64 {
+                let mut res = 0;
+                let mut offset = 0;
+                $(
+                    res |= column_in_word(self.0.$ix.order_bits(), $col::BYTES, offset, word);
+                    offset += $col::BYTES;
+                )+
+                let _ = offset;
+                res
+            }
+        }
+    };
+}
+
+impl_composite!(A: 0);
+impl_composite!(A: 0, B: 1);
+impl_composite!(A: 0, B: 1, C: 2);
+impl_composite!(A: 0, B: 1, C: 2, D: 3);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lcg::LCG;
+    use crate::scheduler::{slice_len, Scheduler};
+    use crate::splitters::ScalarSplitter;
+    use crate::verify::{verify_sorted, Checksum};
+
+    /// 22 bytes of key over three words, in a record that does not evenly divide a slice.
+    type Row = Composite<(u16, Desc<i32>, f64, u64)>;
+
+    #[test]
+    fn composite_keys() {
+        let floats = [
+            f64::NEG_INFINITY,
+            -2.5,
+            -0.0,
+            0.0,
+            1e-300,
+            3.0,
+            f64::MAX,
+            f64::INFINITY,
+        ];
+        let mut random = LCG::with_seed(7);
+        // few distinct values in the first columns, so that ties are broken by the later ones
+        let rows: Vec<Row> = (0..2 * slice_len::<Row>())
+            .map(|_| {
+                let r = random.next();
+                Composite((
+                    (r % 4) as u16,
+                    Desc((r >> 8) as i32 % 3),
+                    floats[(r >> 16) as usize % floats.len()],
+                    random.next() % 1000,
+                ))
+            })
+            .collect();
+
+        let mut input = rows.clone();
+        let mut output = vec![Row::default(); rows.len()];
+        Scheduler::new().split(&mut input, &mut output, &mut ScalarSplitter::new());
+        verify_sorted(&output, Checksum::of(&rows)).unwrap();
+
+        let mut expected = rows;
+        expected.sort_unstable_by(|Composite(x), Composite(y)| {
+            (x.0.cmp(&y.0))
+                .then(y.1 .0.cmp(&x.1 .0))
+                .then(x.2.total_cmp(&y.2))
+                .then(x.3.cmp(&y.3))
+        });
+        assert_eq!(output, expected);
+        // `==` does not tell -0.0 from 0.0
+        assert!(output
+            .iter()
+            .zip(&expected)
+            .all(|(a, b)| a.0 .2.to_bits() == b.0 .2.to_bits()));
+    }
+
+    #[test]
+    fn u32_keys() {
+        // two distinct values, each in more than a slice, so that buckets are split until the key bytes run out
+        let mut random = LCG::with_seed(9);
+        let keys: Vec<u32> = (0..3 * slice_len::<u32>())
+            .map(|_| (random.next() >> 63) as u32 * 0x0101_0101)
+            .collect();
+        let mut input = keys.clone();
+        let mut output = vec![0; keys.len()];
+        let mut sched = Scheduler::new();
+        sched.enable_stats();
+        let stats = sched
+            .split(&mut input, &mut output, &mut ScalarSplitter::new())
+            .unwrap();
+        drop(sched);
+
+        // the low half of the key is always zero, so it is not split on
+        assert_eq!(stats.levels.len(), u32::KEY_BYTES);
+        let mut expected = keys;
+        expected.sort_unstable();
+        assert!(output == expected);
+    }
+
+    #[test]
+    fn composite_key_words() {
+        let row = Composite((0xABCDu16, 0x01234567u32, 0x89u8));
+        assert_eq!(<Composite<(u16, u32, u8)>>::KEY_WORDS, 1);
+        assert_eq!(<Composite<(u16, u32, u8)>>::KEY_BYTES, 7);
+        assert_eq!(row.key(), 0xABCD_0123_4567_8900);
+
+        let row = Composite((1u64, Desc(0u8)));
+        assert_eq!(row.key_word(0), 1);
+        assert_eq!(row.key_word(1), 0xFF << 56);
+    }
+}
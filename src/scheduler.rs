@@ -1,7 +1,15 @@
-use std::alloc::{dealloc, Layout};
+use std::fmt;
 use std::marker::PhantomData;
 use std::mem::{size_of, swap};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use crate::allocators::{GlobalSlices, SliceAllocator, SlicePool};
+use crate::numa::pin_current_thread;
+use crate::observer::{NoObserver, Observer, Phase};
+use crate::records::Record;
 use crate::splitters::Splitter;
 
 pub const SLICE_SIZE_BYTES: usize = 0x10000; // 64 KB
@@ -10,70 +18,376 @@ pub const NUM_BUCKETS: usize = 1 << 8;
 pub const MAX_LEVEL_SPLIT: u8 = 8;
 pub const USE_SMALL_SPLIT: bool = true;
 
-pub struct SplittingBucket<'a> {
-    pub children: Box<[UnsplitBucket<'a>; NUM_BUCKETS]>,
+/// The number of records of type `R` that fit in one slice.
+///
+/// If `size_of::<R>()` is not a power of two, the last few bytes of each slice are left unused.
+pub const fn slice_len<R>() -> usize {
+    SLICE_SIZE_BYTES / size_of::<R>()
 }
 
-pub struct SplitBucket<'a> {
-    pub children: Box<[Bucket<'a>; NUM_BUCKETS]>,
+pub struct SplittingBucket<'a, R = u64> {
+    pub children: Box<[UnsplitBucket<'a, R>; NUM_BUCKETS]>,
 }
 
-#[derive(Default)]
-pub struct UnsplitBucket<'a> {
+pub struct SplitBucket<'a, R = u64> {
+    pub children: Box<[Bucket<'a, R>; NUM_BUCKETS]>,
+}
+
+pub struct UnsplitBucket<'a, R = u64> {
     // read-only but unique
-    pub slices: Vec<&'a mut [u64]>,
+    pub slices: Vec<&'a mut [R]>,
 }
 
-pub enum Bucket<'a> {
-    Split(SplitBucket<'a>),
-    Unsplit(UnsplitBucket<'a>),
+pub enum Bucket<'a, R = u64> {
+    Split(SplitBucket<'a, R>),
+    Unsplit(UnsplitBucket<'a, R>),
     Sorted,
 }
 
-pub struct ActiveSlices<'a> {
-    ptrs: Box<[*mut u64; NUM_BUCKETS]>,
-    phantom: PhantomData<&'a mut u64>,
+pub struct ActiveSlices<'a, R = u64> {
+    ptrs: Box<[*mut R; NUM_BUCKETS]>,
+    phantom: PhantomData<&'a mut R>,
+}
+
+/// Where the time and memory of a sort went, see `Scheduler::enable_stats`.
+#[derive(Clone, Debug, Default)]
+pub struct SplitStats {
+    pub total_time: Duration,
+    /// `levels[0]` is the L0 split. Time spent in the base case is not part of any level.
+    pub levels: Vec<LevelStats>,
+    /// Buckets sorted with `Splitter::split_small`.
+    pub base_cases: usize,
+    pub base_case_time: Duration,
+    /// Buckets sorted with `Splitter::sort_in_place`, since splitting them could have gone over the memory budget.
+    pub buckets_sorted_in_place: usize,
+    /// Slices taken from the allocator.
+    pub slices_allocated: usize,
+    /// Slices taken from the free slices, which were either already used or part of the input.
+    pub slices_reused: usize,
+    /// Slices are only released at the end of a sort, so this is the memory of every allocated slice, including the
+    /// ones pooled from earlier sorts. For `split_parallel`, this adds up the peaks of all threads.
+    pub peak_slice_bytes: usize,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct LevelStats {
+    pub time: Duration,
+    pub buckets_split: usize,
+    pub records_split: usize,
+}
+
+impl SplitStats {
+    fn record_split(&mut self, level: usize, records: usize, time: Duration) {
+        if self.levels.len() <= level {
+            self.levels.resize_with(level + 1, Default::default);
+        }
+        let level = &mut self.levels[level];
+        level.time += time;
+        level.buckets_split += 1;
+        level.records_split += records;
+    }
+
+    /// Add up the stats of a part of the sort that ran at the same time as this one.
+    fn merge(&mut self, other: &SplitStats) {
+        if self.levels.len() < other.levels.len() {
+            self.levels.resize_with(other.levels.len(), Default::default);
+        }
+        for (level, other) in self.levels.iter_mut().zip(&other.levels) {
+            level.time += other.time;
+            level.buckets_split += other.buckets_split;
+            level.records_split += other.records_split;
+        }
+        self.base_cases += other.base_cases;
+        self.base_case_time += other.base_case_time;
+        self.buckets_sorted_in_place += other.buckets_sorted_in_place;
+        self.slices_allocated += other.slices_allocated;
+        self.slices_reused += other.slices_reused;
+        self.peak_slice_bytes += other.peak_slice_bytes;
+    }
+}
+
+/// What happened during a sort, to be passed on to the `Observer`.
+enum Event {
+    LevelStart(usize),
+    BucketSplit {
+        level: usize,
+        bucket_id: u64,
+        len: usize,
+    },
+    RecordsDone(usize),
+}
+
+#[derive(Default)]
+struct Progress {
+    /// we have seen a bucket split at every level below this
+    levels_started: usize,
+    records_done: usize,
+    records_total: usize,
+    /// the last progress we reported, in units of `1 / PROGRESS_STEPS`
+    steps_reported: usize,
+}
+
+/// How often to report progress during a sort.
+const PROGRESS_STEPS: usize = 1024;
+
+/// What to do with a bucket when splitting it could take a sort over its memory budget.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverBudget {
+    /// Sort the bucket where it lands in the output, with `Splitter::sort_in_place`. This needs no slices, but is
+    /// slower than splitting.
+    SortInPlace,
+    /// Stop sorting, and fail with `BudgetExceeded`.
+    Fail,
+}
+
+/// A sort stopped since it would have gone over its memory budget, see `Scheduler::set_memory_budget`.
+///
+/// The output still holds every record, but only part of it is sorted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BudgetExceeded {
+    pub budget_bytes: usize,
+}
+
+impl fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the sort needs more than its memory budget of {} bytes", self.budget_bytes)
+    }
+}
+
+impl std::error::Error for BudgetExceeded {}
+
+/// The records that `split_in_place` sorts, whose slices are the input, free slices, and the output all at once.
+struct InPlace {
+    start: usize,
+    num_slices: usize,
+    /// whether each slice holds records that are not in the output yet
+    live: Vec<bool>,
+    /// where each slice moved to (or 0), when it was in the way of the output
+    moved_to: Vec<usize>,
+    /// the output is written up to this address, so the slices before it are never handed out again
+    written_until: usize,
+}
+
+impl InPlace {
+    fn new(start: usize, num_slices: usize) -> Self {
+        Self {
+            start,
+            num_slices,
+            // the slices are the input
+            live: vec![true; num_slices],
+            moved_to: vec![0; num_slices],
+            written_until: start,
+        }
+    }
+
+    /// The index of the slice that `addr` is in, if it is one of ours.
+    fn slice_ix(&self, addr: usize) -> Option<usize> {
+        let ix = addr.checked_sub(self.start)? / SLICE_SIZE_BYTES;
+        (ix < self.num_slices).then_some(ix)
+    }
+
+    /// Whether `addr` is in one of our slices that is part of the output now.
+    fn is_written(&self, addr: usize) -> bool {
+        self.slice_ix(addr).is_some() && addr < self.written_until
+    }
+}
+
+/// Where `split_tree` puts the records of each bucket once they are sorted.
+pub(crate) enum Sink<'o, R> {
+    /// In order, into a slice with room for every record.
+    Slice(&'o mut [R]),
+    /// Handed to `visit` in order, a sorted run at a time. Base cases are sorted into `scratch`.
+    Visit {
+        visit: &'o mut dyn FnMut(&[R]),
+        scratch: Vec<R>,
+    },
 }
 
-pub struct Scheduler<'a> {
-    allocations: Vec<*mut u64>,
-    free_slices: Vec<*mut u64>,
-    top_level: Option<Bucket<'a>>,
-    phantom: PhantomData<&'a mut u64>,
+impl<'o, R> Sink<'o, R> {
+    pub(crate) fn visit(visit: &'o mut dyn FnMut(&[R])) -> Self {
+        Sink::Visit {
+            visit,
+            scratch: Vec::new(),
+        }
+    }
+
+    /// Hand the run in `scratch` on, if we have a visitor.
+    fn visit_scratch(&mut self) {
+        if let Sink::Visit { visit, scratch } = self {
+            visit(scratch);
+        }
+    }
 }
 
-impl<'a> Scheduler<'a> {
-    const SLICE_LAYOUT: Layout = Layout::from_size_align(SLICE_SIZE_BYTES, SLICE_SIZE_BYTES)
-        .ok()
-        .expect("BUF_SIZE_BYTES and SLICE_SIZE_BYTES should be powers of two");
+#[derive(Clone)]
+struct Budget {
+    bytes: usize,
+    /// the slices held by every scheduler of the current sort, so the threads of `split_parallel` share this
+    held: Arc<AtomicUsize>,
+    over_budget: OverBudget,
+    /// set once a bucket could not get its slices with `OverBudget::Fail`
+    exceeded: bool,
+}
 
-    fn free_slice<'b>(&'b mut self, slice: &'b mut [u64]) {
+pub struct Scheduler<'a, R = u64> {
+    pool: SlicePool<'a>,
+    free_slices: Vec<*mut R>,
+    top_level: Option<Bucket<'a, R>>,
+    observer: Box<dyn Observer + 'a>,
+    /// in the worker threads of `split_parallel`, events are sent to the scheduler that started them, rather than
+    /// to our own observer
+    events: Option<Sender<Event>>,
+    progress: Progress,
+    stats: Option<SplitStats>,
+    budget: Option<Budget>,
+    in_place: Option<InPlace>,
+    /// the CPUs that the threads of `split_parallel` run on
+    pinned_cpus: Option<Vec<usize>>,
+    phantom: PhantomData<&'a mut R>,
+}
+
+impl<'a, R: Copy> Scheduler<'a, R> {
+    pub(crate) fn free_slice<'b>(&'b mut self, slice: &'b mut [R]) {
         let ptr = slice.as_mut_ptr();
-        assert!(ptr.is_aligned_to(SLICE_SIZE_BYTES));
+        // when `R` does not evenly divide a slice, input slices after the first do not start on a slice boundary,
+        // and cannot be reused
+        if !ptr.is_aligned_to(SLICE_SIZE_BYTES) {
+            return;
+        }
+        if let Some(in_place) = &mut self.in_place {
+            if let Some(ix) = in_place.slice_ix(ptr as usize) {
+                in_place.live[ix] = false;
+                if in_place.is_written(ptr as usize) {
+                    return;
+                }
+            }
+        }
         self.free_slices.push(ptr);
     }
 
-    fn get_slice(&mut self) -> *mut u64 {
-        if let Some(ptr) = self.free_slices.pop() {
+    fn get_slice(&mut self) -> *mut R {
+        while let Some(ptr) = self.free_slices.pop() {
             debug_assert!(ptr.is_aligned_to(SLICE_SIZE_BYTES));
+            if let Some(in_place) = &mut self.in_place {
+                if in_place.is_written(ptr as usize) {
+                    continue;
+                }
+                if let Some(ix) = in_place.slice_ix(ptr as usize) {
+                    in_place.live[ix] = true;
+                }
+            }
+            if let Some(stats) = &mut self.stats {
+                stats.slices_reused += 1;
+            }
             return ptr;
         }
 
-        let ptr = unsafe { std::alloc::alloc(Self::SLICE_LAYOUT) as *mut u64 };
+        // with a budget, `reserve_free_slices` made sure that we do not get here
+        debug_assert!(self.budget.is_none(), "ran out of reserved slices");
+        let Some(ptr) = self.allocate_slice() else {
+            panic!("Could not allocate new free slice");
+        };
+        ptr
+    }
+
+    /// A new slice from the pool's allocator, if there is one within the budget.
+    fn allocate_slice(&mut self) -> Option<*mut R> {
+        if let Some(budget) = &self.budget {
+            if budget.held.fetch_add(1, Ordering::Relaxed) >= budget.bytes / SLICE_SIZE_BYTES {
+                budget.held.fetch_sub(1, Ordering::Relaxed);
+                return None;
+            }
+        }
+        let Some(addr) = self.pool.allocate() else {
+            if let Some(budget) = &self.budget {
+                budget.held.fetch_sub(1, Ordering::Relaxed);
+            }
+            return None;
+        };
+        let ptr = addr as *mut R;
 
         debug_assert!((ptr as usize & (SLICE_SIZE_BYTES - 1)) == 0);
 
-        if ptr.is_null() {
-            // handle_alloc_error(LAYOUT);
-            panic!("Could not allocate new free slice");
+        if let Some(stats) = &mut self.stats {
+            stats.slices_allocated += 1;
+            // we only deallocate at the end of a sort
+            stats.peak_slice_bytes = self.pool.len() * SLICE_SIZE_BYTES;
         }
-        self.allocations.push(ptr);
 
-        ptr
+        Some(ptr)
+    }
+
+    /// With a memory budget, make sure that we have `num_slices` free slices, so that nothing we do until the next
+    /// call allocates. Returns false if that would go over the budget.
+    fn reserve_free_slices(&mut self, num_slices: usize) -> bool {
+        let Some(budget) = &mut self.budget else {
+            return true;
+        };
+        if budget.exceeded {
+            return false;
+        }
+        if let Some(in_place) = &self.in_place {
+            self.free_slices.retain(|&ptr| !in_place.is_written(ptr as usize));
+        }
+        while self.free_slices.len() < num_slices {
+            let Some(ptr) = self.allocate_slice() else {
+                let budget = self.budget.as_mut().unwrap();
+                budget.exceeded = budget.over_budget == OverBudget::Fail;
+                return false;
+            };
+            self.free_slices.push(ptr);
+        }
+        true
+    }
+
+    /// With `split_in_place`, move the records that are in the way of writing `len` records of output at `start`
+    /// elsewhere, and make sure that those slices are never handed out again.
+    fn make_room(&mut self, start: *mut R, len: usize) {
+        let Some(in_place) = &mut self.in_place else {
+            return;
+        };
+        if len == 0 {
+            return;
+        }
+        let end = start as usize + len * size_of::<R>();
+        debug_assert!(start as usize >= in_place.written_until);
+        in_place.written_until = end;
+        let first = in_place.slice_ix(start as usize).expect("the output is in the records");
+        let last = in_place.slice_ix(end - 1).expect("the output is in the records");
+
+        for ix in first..=last {
+            if !self.in_place.as_ref().unwrap().live[ix] {
+                continue;
+            }
+            // this cannot be one of the slices we are about to write, since they count as written
+            let dest = self.get_slice();
+            let in_place = self.in_place.as_mut().unwrap();
+            let src = (in_place.start + ix * SLICE_SIZE_BYTES) as *const R;
+            unsafe { std::ptr::copy_nonoverlapping(src, dest, slice_len::<R>()) };
+            in_place.moved_to[ix] = dest as usize;
+            in_place.live[ix] = false;
+        }
+    }
+
+    /// With `split_in_place`, point `slices` to where `make_room` moved them.
+    fn resolve_slices(&self, slices: &mut [&'a mut [R]]) {
+        let Some(in_place) = &self.in_place else {
+            return;
+        };
+        for slice in slices {
+            let mut addr = slice.as_ptr() as usize;
+            // a slice can be moved several times, since it can be moved into a slice that is later in the way too
+            while let Some(ix) = in_place.slice_ix(addr).filter(|&ix| in_place.moved_to[ix] != 0) {
+                addr = in_place.moved_to[ix] + (addr - in_place.start) % SLICE_SIZE_BYTES;
+            }
+            if addr != slice.as_ptr() as usize {
+                *slice = unsafe { std::slice::from_raw_parts_mut(addr as *mut R, slice.len()) };
+            }
+        }
     }
 }
 
-impl<'a> Default for ActiveSlices<'a> {
+impl<'a, R> Default for ActiveSlices<'a, R> {
     fn default() -> Self {
         Self {
             ptrs: Box::new([std::ptr::null_mut(); NUM_BUCKETS]),
@@ -82,19 +396,25 @@ impl<'a> Default for ActiveSlices<'a> {
     }
 }
 
-impl<'a> ActiveSlices<'a> {
-    fn len_of_ptr(ptr: *mut u64) -> usize {
+impl<'a, R: Copy> ActiveSlices<'a, R> {
+    fn len_of_ptr(ptr: *mut R) -> usize {
         if ptr.is_null() {
             return 0;
         }
-        let offset = (ptr as usize & (SLICE_SIZE_BYTES - 1)) / size_of::<u64>();
+        let offset = (ptr as usize & (SLICE_SIZE_BYTES - 1)) / size_of::<R>();
         if offset == 0 {
-            SLICE_SIZE
+            slice_len::<R>()
         } else {
             offset
         }
     }
 
+    /// Whether we are at the end of a slice (or have no slice yet), so we cannot append here.
+    fn is_full(ptr: *mut R) -> bool {
+        // for a power-of-two sized `R`, this is just an alignment check
+        (ptr as usize & (SLICE_SIZE_BYTES - 1)).is_multiple_of(slice_len::<R>() * size_of::<R>())
+    }
+
     pub fn len_of_bucket(&self, ix: usize) -> usize {
         Self::len_of_ptr(self.ptrs[ix])
     }
@@ -103,7 +423,7 @@ impl<'a> ActiveSlices<'a> {
         (0..256).map(|ix| self.len_of_bucket(ix)).sum()
     }
 
-    pub fn total_lens_of_full_buckets(&self, bucket: &SplittingBucket<'a>) -> usize {
+    pub fn total_lens_of_full_buckets(&self, bucket: &SplittingBucket<'a, R>) -> usize {
         bucket
             .children
             .iter()
@@ -115,21 +435,21 @@ impl<'a> ActiveSlices<'a> {
 
     pub fn insert_element(
         &mut self,
-        bucket: &mut SplittingBucket<'a>,
-        sched: &mut Scheduler,
-        el: u64,
+        bucket: &mut SplittingBucket<'a, R>,
+        sched: &mut Scheduler<R>,
+        el: R,
         ix: usize,
     ) {
         let ptr = &mut self.ptrs[ix];
-        if ptr.is_aligned_to(SLICE_SIZE_BYTES) {
+        if Self::is_full(*ptr) {
             // we are at the end of a slice, so we cannot append here
             if !ptr.is_null() {
                 // put this slice into the child bucket, and get a new slice
                 let slice = unsafe {
                     // reset pointer to start of slice
-                    let start_ptr = ptr.sub(SLICE_SIZE);
+                    let start_ptr = ptr.sub(slice_len::<R>());
                     // dbg!(("full", start_ptr, &*ptr, ix));
-                    std::slice::from_raw_parts_mut(start_ptr, SLICE_SIZE)
+                    std::slice::from_raw_parts_mut(start_ptr, slice_len::<R>())
                 };
                 bucket.children[ix].slices.push(slice);
             }
@@ -143,12 +463,12 @@ impl<'a> ActiveSlices<'a> {
 
     pub fn insert_elements(
         &mut self,
-        bucket: &mut SplittingBucket<'a>,
-        sched: &mut Scheduler,
-        els: &[u64],
+        bucket: &mut SplittingBucket<'a, R>,
+        sched: &mut Scheduler<R>,
+        els: &[R],
         ix: usize,
     ) {
-        if self.len_of_bucket(ix) >= els.len() {
+        if !self.ptrs[ix].is_null() && slice_len::<R>() - self.len_of_bucket(ix) >= els.len() {
             // we have enough space, just insert them all
             // happy path!
             let ptr = &mut self.ptrs[ix];
@@ -163,13 +483,13 @@ impl<'a> ActiveSlices<'a> {
         }
     }
 
-    pub fn complete(self, bucket: &mut SplittingBucket<'a>) {
+    pub fn complete(self, bucket: &mut SplittingBucket<'a, R>) {
         for (ptr, child) in self.ptrs.into_iter().zip(bucket.children.iter_mut()) {
             if !ptr.is_null() {
                 let slice = unsafe {
                     let els_in_slice = Self::len_of_ptr(ptr);
                     let start_ptr = ptr.sub(els_in_slice);
-                    debug_assert!(els_in_slice <= SLICE_SIZE);
+                    debug_assert!(els_in_slice <= slice_len::<R>());
                     // dbg!(("partial", start_ptr, ptr, els_in_slice /*,idx*/,));
                     std::slice::from_raw_parts_mut(start_ptr, els_in_slice)
                 };
@@ -179,25 +499,32 @@ impl<'a> ActiveSlices<'a> {
     }
 }
 
-impl<'a> Default for SplittingBucket<'a> {
+impl<'a, R> Default for SplittingBucket<'a, R> {
     fn default() -> Self {
         // we would like to use #[derive(Default)] on SplittingBucket, but we don't have
         // `[T; 256]: Default where T: Default`
-        const CHILD: UnsplitBucket = UnsplitBucket { slices: vec![] };
         Self {
-            children: Box::new([CHILD; 256]),
+            children: Box::new(std::array::from_fn(|_| UnsplitBucket::default())),
         }
     }
 }
 
-impl<'a> UnsplitBucket<'a> {
+impl<'a, R> Default for UnsplitBucket<'a, R> {
+    fn default() -> Self {
+        // #[derive(Default)] would require `R: Default`
+        Self { slices: vec![] }
+    }
+}
+
+impl<'a, R: Copy> UnsplitBucket<'a, R> {
     fn split(
         self,
-        sched: &mut Scheduler<'a>,
-        splitter: &mut dyn Splitter<'a>,
+        sched: &mut Scheduler<'a, R>,
+        splitter: &mut dyn Splitter<'a, R>,
+        word: usize,
         shift: u8,
         mask: u64,
-    ) -> SplittingBucket<'a> {
+    ) -> SplittingBucket<'a, R> {
         let slices = self.slices;
         let mut dests = ActiveSlices::default();
         let mut res = SplittingBucket::default();
@@ -207,7 +534,7 @@ impl<'a> UnsplitBucket<'a> {
         debug_assert_eq!(dests.total_lens_of_buckets(), 0);
 
         for slice in slices {
-            splitter.split(slice, shift, mask, &mut dests, &mut res, sched);
+            splitter.split(slice, word, shift, mask, &mut dests, &mut res, sched);
 
             debug_assert_eq!(
                 dests.total_lens_of_full_buckets(&res),
@@ -223,53 +550,706 @@ impl<'a> UnsplitBucket<'a> {
     }
 }
 
-impl<'a> From<SplittingBucket<'a>> for SplitBucket<'a> {
-    fn from(val: SplittingBucket<'a>) -> Self {
+impl<'a, R> From<SplittingBucket<'a, R>> for SplitBucket<'a, R> {
+    fn from(val: SplittingBucket<'a, R>) -> Self {
         Self {
             children: Box::new(val.children.map(Bucket::Unsplit)),
         }
     }
 }
 
-impl<'a> Default for Scheduler<'a> {
+impl<'a, R> Default for Scheduler<'a, R> {
     fn default() -> Self {
         Self {
-            allocations: vec![],
+            pool: SlicePool::new(&GlobalSlices),
             free_slices: vec![],
             top_level: None,
+            observer: Box::new(NoObserver),
+            events: None,
+            progress: Progress::default(),
+            stats: None,
+            budget: None,
+            in_place: None,
+            pinned_cpus: None,
             phantom: PhantomData,
         }
     }
 }
 
-impl<'a> Scheduler<'a> {
+impl<'a, R: Copy> Scheduler<'a, R> {
     pub fn new() -> Self {
-        // TODO preallocate free_slices here
         Self {
-            allocations: vec![],
+            pool: SlicePool::new(&GlobalSlices),
             free_slices: vec![],
             top_level: None,
+            observer: Box::new(NoObserver),
+            events: None,
+            progress: Progress::default(),
+            stats: None,
+            budget: None,
+            in_place: None,
+            pinned_cpus: None,
             phantom: PhantomData,
         }
     }
 
+    /// Report the progress of each sort to `observer`. Pass `&mut observer` to keep hold of it.
+    pub fn set_observer(&mut self, observer: impl Observer + 'a) {
+        self.observer = Box::new(observer);
+    }
+
+    /// A scheduler that starts with the slices of `pool`, and its allocator and limit.
+    ///
+    /// The scheduler borrows its input and output for as long as it lives, so to keep slices warm across sorts,
+    /// keep the pool outside and hand it back with `return_pool` after each sort.
+    pub fn with_pool<'p: 'a>(pool: &mut SlicePool<'p>) -> Self {
+        let mut res = Self {
+            pool: SlicePool::new(pool.allocator()),
+            ..Self::new()
+        };
+        res.pool.set_limit(pool.limit());
+        res.pool.append(pool);
+        res
+    }
+
+    /// Move the slices of our pool into `pool`, which must have the same allocator.
+    pub fn return_pool(mut self, pool: &mut SlicePool<'_>) {
+        pool.append(&mut self.pool);
+    }
+
+    pub fn pool(&self) -> &SlicePool<'a> {
+        &self.pool
+    }
+
+    pub fn pool_mut(&mut self) -> &mut SlicePool<'a> {
+        &mut self.pool
+    }
+
+    /// Take slices from `allocator` rather than the global allocator.
+    ///
+    /// The threads of `split_parallel` share it. This starts a new, empty pool with the same limit.
+    pub fn set_allocator(&mut self, allocator: &'a dyn SliceAllocator) {
+        let mut pool = SlicePool::new(allocator);
+        pool.set_limit(self.pool.limit());
+        self.pool = pool;
+    }
+
+    /// Collect `SplitStats` during each sort, which `split` and `split_parallel` then return.
+    ///
+    /// This costs a few clock reads for every bucket, so it is off by default.
+    pub fn enable_stats(&mut self) {
+        self.stats = Some(SplitStats::default());
+    }
+
+    /// Hold at most `bytes` of slices during each sort, counting the ones already in the pool and those of every
+    /// thread of `split_parallel`, but not the input (whose slices are reused as soon as they are split).
+    ///
+    /// Before splitting a bucket, we make sure that we have enough free slices to split it. If that would go over
+    /// the budget, or the allocator runs out, the bucket is handled as `over_budget` says.
+    pub fn set_memory_budget(&mut self, bytes: usize, over_budget: OverBudget) {
+        self.budget = Some(Budget {
+            bytes,
+            held: Arc::default(),
+            over_budget,
+            exceeded: false,
+        });
+    }
+
+    /// Allocate slices as needed, as by default.
+    pub fn clear_memory_budget(&mut self) {
+        self.budget = None;
+    }
+
+    /// Pin thread `i` of `split_parallel` to CPU `cpus[i % cpus.len()]`, so that it stays near the memory it
+    /// first touched. `Topology::spread` picks CPUs on every NUMA node; see also `NumaSlices`.
+    ///
+    /// A thread that cannot be pinned (say, since the CPU is not in this process's affinity mask) runs wherever the
+    /// kernel puts it, as by default.
+    pub fn set_pinned_cpus(&mut self, cpus: Vec<usize>) {
+        assert!(!cpus.is_empty());
+        self.pinned_cpus = Some(cpus);
+    }
+
+    /// Let the kernel place the threads of `split_parallel`, as by default.
+    pub fn clear_pinned_cpus(&mut self) {
+        self.pinned_cpus = None;
+    }
+
+    /// The current time, if we are collecting stats.
+    fn stats_start(&self) -> Option<Instant> {
+        self.stats.as_ref().map(|_| Instant::now())
+    }
+
+    fn record_split(&mut self, level: usize, records: usize, start: Option<Instant>) {
+        if let (Some(stats), Some(start)) = (&mut self.stats, start) {
+            stats.record_split(level, records, start.elapsed());
+        }
+    }
+
+    /// Reset the stats (if we collect them) for a new sort.
+    fn start_stats(&mut self) -> Option<Instant> {
+        if let Some(stats) = &mut self.stats {
+            *stats = SplitStats {
+                peak_slice_bytes: self.pool.len() * SLICE_SIZE_BYTES,
+                ..SplitStats::default()
+            };
+        }
+        self.stats_start()
+    }
+
+    /// The stats (if we collect them) of the sort that began at `start`.
+    fn finish_stats(&mut self, start: Option<Instant>) -> Option<SplitStats> {
+        let stats = self.stats.as_mut()?;
+        if let Some(start) = start {
+            stats.total_time = start.elapsed();
+        }
+        Some(stats.clone())
+    }
+
+    fn enter_phase(&mut self, phase: Phase) {
+        self.observer.on_phase(phase);
+    }
+
+    fn notify(&mut self, event: Event) {
+        let progress = &mut self.progress;
+        match event {
+            Event::LevelStart(level) if level < progress.levels_started => return,
+            Event::LevelStart(level) => progress.levels_started = level + 1,
+            Event::RecordsDone(len) => {
+                progress.records_done += len;
+                if self.events.is_some() {
+                    // sent in bulk by `flush_records_done`
+                    return;
+                }
+            }
+            Event::BucketSplit { .. } => (),
+        }
+
+        if let Some(events) = &self.events {
+            // the receiver only stops listening once every worker is done
+            let _ = events.send(event);
+            return;
+        }
+
+        match event {
+            Event::LevelStart(level) => self.observer.on_level_start(level),
+            Event::BucketSplit {
+                level,
+                bucket_id,
+                len,
+            } => self.observer.on_bucket_split(level, bucket_id, len),
+            Event::RecordsDone(_) => {
+                let steps = progress.records_done * PROGRESS_STEPS / progress.records_total.max(1);
+                if steps > progress.steps_reported {
+                    progress.steps_reported = steps;
+                    self.observer
+                        .on_progress(progress.records_done as f64 / progress.records_total as f64);
+                }
+            }
+        }
+    }
+
+    /// Send the records done so far to the scheduler that started this worker.
+    fn flush_records_done(&mut self) {
+        if let Some(events) = &self.events {
+            let _ = events.send(Event::RecordsDone(self.progress.records_done));
+            self.progress.records_done = 0;
+        }
+    }
+
+    /// Keep the slices of a finished sort for the next one, up to the pool limit, and give the rest back to the
+    /// allocator.
+    fn recycle_slices(&mut self) {
+        // the free slices that are not in the pool are part of the input, which belongs to the caller again
+        self.free_slices.clear();
+        self.pool.trim();
+    }
+}
+
+impl<'a, R: Record> Scheduler<'a, R> {
+    /// Sort `input` into `output`, by key.
+    ///
+    /// Keys longer than one word are split one byte at a time through all of their words. The number of levels is
+    /// `R::KEY_BYTES`, so bytes that are the same in every key (such as the low half of a `u32` key) are not split on.
+    ///
+    /// Splitting never reorders records with equal keys, so the sort is stable whenever `splitter.split_small` and
+    /// `splitter.sort_in_place` are.
+    ///
+    /// Returns the stats of this sort, if they were enabled with `enable_stats`.
+    ///
+    /// Panics if the sort goes over a memory budget set with `OverBudget::Fail`, see `try_split`.
     pub fn split(
         &mut self,
-        input: &'a mut [u64],
-        output: &'a mut [u64],
-        splitter: &mut dyn Splitter<'a>,
+        input: &'a mut [R],
+        output: &'a mut [R],
+        splitter: &mut dyn Splitter<'a, R>,
+    ) -> Option<SplitStats> {
+        self.try_split(input, output, splitter)
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Like `split`, but returns an error if the sort goes over a memory budget set with `OverBudget::Fail`.
+    pub fn try_split(
+        &mut self,
+        input: &'a mut [R],
+        output: &'a mut [R],
+        splitter: &mut dyn Splitter<'a, R>,
+    ) -> Result<Option<SplitStats>, BudgetExceeded> {
+        let start = self.start_sort(input.len());
+        if !self.reserve_for_l0(input) {
+            output.copy_from_slice(input);
+            self.sort_over_budget(output, splitter);
+            return self.finish_sort(start);
+        }
+        let l0 = self.split_l0(input, splitter);
+
+        let mut top_level = Bucket::Split(l0.into());
+
+        self.split_tree(&mut top_level, 0, 0, &mut Sink::Slice(output), splitter);
+
+        self.top_level = Some(top_level);
+        self.finish_sort(start)
+    }
+
+    /// Sort `records` by key, like `split`, but into `records` itself instead of a second buffer.
+    ///
+    /// The slices of `records` are the input, the free slices that splitting writes to, and finally the output. Before
+    /// a bucket is written to its place in the output, the records still in the way are moved to free slices, so on
+    /// top of `records` the sort needs only about the partly filled slices of the buckets.
+    ///
+    /// `records` must start on a `SLICE_SIZE_BYTES` boundary, and its length must be a multiple of the slice length.
+    /// This sorts on one thread, and does not support a memory budget.
+    pub fn split_in_place(
+        &mut self,
+        records: &'a mut [R],
+        splitter: &mut dyn Splitter<'a, R>,
+    ) -> Option<SplitStats> {
+        assert!(size_of::<R>() != 0 && SLICE_SIZE_BYTES.is_multiple_of(size_of::<R>()));
+        assert!(
+            records.as_ptr().is_aligned_to(SLICE_SIZE_BYTES),
+            "split_in_place needs records that start on a slice boundary"
+        );
+        assert!(
+            self.budget.is_none(),
+            "split_in_place does not support a memory budget"
+        );
+        let len = records.len();
+        let start_addr = records.as_mut_ptr();
+        self.in_place = Some(InPlace::new(
+            start_addr as usize,
+            len / slice_len::<R>(),
+        ));
+        let start = self.start_sort(len);
+
+        // `make_room` makes sure that we only write to the output where no input records are left, and that those
+        // slices are never handed out again
+        let (input, output) = unsafe {
+            (
+                std::slice::from_raw_parts_mut(start_addr, len),
+                std::slice::from_raw_parts_mut(start_addr, len),
+            )
+        };
+        let l0 = self.split_l0(input, splitter);
+
+        let mut top_level = Bucket::Split(l0.into());
+
+        self.split_tree(&mut top_level, 0, 0, &mut Sink::Slice(output), splitter);
+
+        self.in_place = None;
+        self.top_level = Some(top_level);
+        self.finish_sort(start)
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Sort `input` by key, like `split`, but hand the sorted records to `visit` instead of writing them to an output
+    /// buffer.
+    ///
+    /// `visit` is called with runs of records in order, each as soon as its bucket is sorted: a base case sorted into
+    /// a scratch slice, or a slice of records whose keys compare equal. So beyond `input` (whose slices are used as
+    /// scratch space) the sort only needs the slices that splitting holds, and a slice for the base case.
+    ///
+    /// This sorts on one thread, and does not support a memory budget.
+    pub fn split_visit(
+        &mut self,
+        input: &'a mut [R],
+        splitter: &mut dyn Splitter<'a, R>,
+        mut visit: impl FnMut(&[R]),
+    ) -> Option<SplitStats> {
+        assert!(
+            self.budget.is_none(),
+            "split_visit does not support a memory budget"
+        );
+        let start = self.start_sort(input.len());
+        let l0 = self.split_l0(input, splitter);
+
+        let mut top_level = Bucket::Split(l0.into());
+
+        self.split_tree(&mut top_level, 0, 0, &mut Sink::visit(&mut visit), splitter);
+
+        self.top_level = Some(top_level);
+        self.finish_sort(start)
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Like `split`, but once the first level is split, its buckets are split on `num_threads` threads.
+    ///
+    /// Each thread has its own scheduler (so its own free slices, though they share our pool's allocator) and its own
+    /// clone of `splitter`, and writes to the part of `output` that its buckets will be sorted into.
+    pub fn split_parallel<S>(
+        &mut self,
+        input: &'a mut [R],
+        output: &'a mut [R],
+        splitter: &S,
+        num_threads: usize,
+    ) -> Option<SplitStats>
+    where
+        R: Send,
+        S: Splitter<'a, R> + Clone + Send,
+    {
+        self.try_split_parallel(input, output, splitter, num_threads)
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Like `split_parallel`, but returns an error if the sort goes over a memory budget set with
+    /// `OverBudget::Fail`.
+    pub fn try_split_parallel<S>(
+        &mut self,
+        input: &'a mut [R],
+        output: &'a mut [R],
+        splitter: &S,
+        num_threads: usize,
+    ) -> Result<Option<SplitStats>, BudgetExceeded>
+    where
+        R: Send,
+        S: Splitter<'a, R> + Clone + Send,
+    {
+        assert!(num_threads > 0);
+        let start = self.start_sort(input.len());
+        if !self.reserve_for_l0(input) {
+            output.copy_from_slice(input);
+            self.sort_over_budget(output, &mut splitter.clone());
+            return self.finish_sort(start);
+        }
+        let l0 = self.split_l0(input, &mut splitter.clone());
+
+        let mut children = SplitBucket::from(l0).children;
+
+        // the output of each bucket starts after every record in the buckets before it
+        let mut work = Vec::with_capacity(NUM_BUCKETS);
+        let mut rest = output;
+        for (ix, child) in children.iter_mut().enumerate() {
+            let len = match child {
+                Bucket::Unsplit(UnsplitBucket { slices }) => slices.iter().map(|slice| slice.len()).sum(),
+                _ => 0,
+            };
+            let (this_output, next) = rest.split_at_mut(len);
+            work.push((ix, child, this_output));
+            rest = next;
+        }
+        debug_assert!(rest.is_empty());
+
+        // hand out buckets largest first, so one large bucket does not hold up the end of the sort
+        work.sort_by_key(|(_, _, output)| output.len());
+        let work = std::sync::Mutex::new(work);
+
+        // share out our free slices (the pool and the input), as addresses since pointers are not `Send`
+        let free_slices = self.free_slices.drain(..).map(|ptr| ptr as usize).collect::<Vec<_>>();
+        let mut free_slices = free_slices
+            .chunks(free_slices.len().div_ceil(num_threads).max(1))
+            .map(<[usize]>::to_vec)
+            .collect::<Vec<_>>();
+        free_slices.resize(num_threads, vec![]);
+
+        let collect_stats = self.stats.is_some();
+        let (events, received) = std::sync::mpsc::channel();
+        let thread_results = std::thread::scope(|scope| {
+            let threads = free_slices
+                .into_iter()
+                .enumerate()
+                .map(|(thread_ix, free_slices)| {
+                    let work = &work;
+                    let mut splitter = splitter.clone();
+                    let events = events.clone();
+                    let allocator = self.pool.allocator();
+                    let budget = self.budget.clone();
+                    let cpu = self.pinned_cpus.as_ref().map(|cpus| cpus[thread_ix % cpus.len()]);
+                    scope.spawn(move || {
+                        if let Some(cpu) = cpu {
+                            // before taking any slices, so that new ones are first touched on our node
+                            let _ = pin_current_thread(cpu);
+                        }
+                        let mut sched = Scheduler::new();
+                        sched.pool = SlicePool::new(allocator);
+                        sched.budget = budget;
+                        sched.free_slices = free_slices.into_iter().map(|addr| addr as *mut R).collect();
+                        sched.events = Some(events);
+                        if collect_stats {
+                            sched.enable_stats();
+                        }
+                        loop {
+                            // take the lock in its own statement, so that it is not held while splitting
+                            let next = work.lock().unwrap().pop();
+                            let Some((ix, child, output)) = next else { break };
+                            let bucket_id = (ix as u64) << ((MAX_LEVEL_SPLIT - 1) * 8);
+                            sched.split_tree(child, 1, bucket_id, &mut Sink::Slice(output), &mut splitter);
+                            sched.flush_records_done();
+                        }
+                        // the slices this thread allocated join our pool
+                        let pool = std::mem::replace(&mut sched.pool, SlicePool::new(allocator));
+                        let exceeded = sched.budget.as_ref().is_some_and(|budget| budget.exceeded);
+                        (sched.stats.take(), pool, exceeded)
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            // pass on what the workers report, until they are all done
+            drop(events);
+            for event in received {
+                self.notify(event);
+            }
+
+            threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        for (thread_stats, mut pool, exceeded) in thread_results {
+            if let (Some(stats), Some(thread_stats)) = (&mut self.stats, thread_stats) {
+                stats.merge(&thread_stats);
+            }
+            self.pool.append(&mut pool);
+            if let Some(budget) = &mut self.budget {
+                budget.exceeded |= exceeded;
+            }
+        }
+
+        self.top_level = Some(Bucket::Split(SplitBucket { children }));
+        self.finish_sort(start)
+    }
+
+    /// Get ready for a `StreamingSorter` to push records. Returns the start time, if we collect stats.
+    pub(crate) fn start_streaming(&mut self) -> Option<Instant> {
+        assert!(
+            self.budget.is_none(),
+            "a StreamingSorter does not support a memory budget"
+        );
+        let start = self.start_sort(0);
+        self.enter_phase(Phase::L0);
+        self.notify(Event::LevelStart(0));
+        start
+    }
+
+    /// Split a batch of `records` pushed to a `StreamingSorter` on the first byte of its key, as part of the L0
+    /// split. Returns the time it took, if we collect stats.
+    pub(crate) fn split_streamed(
+        &mut self,
+        records: &[R],
+        dests: &mut ActiveSlices<'a, R>,
+        l0: &mut SplittingBucket<'a, R>,
+        splitter: &mut dyn Splitter<'a, R>,
+    ) -> Option<Duration> {
+        self.progress.records_total += records.len();
+        let split_start = self.stats_start();
+        splitter.split(records, 0, 56, 0xff, dests, l0, self);
+        split_start.map(|start| start.elapsed())
+    }
+
+    /// Sort the buckets of a `StreamingSorter`'s L0 split, of `len` records, into `output`.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn finish_streaming(
+        &mut self,
+        mut l0: SplittingBucket<'a, R>,
+        dests: ActiveSlices<'a, R>,
+        split_time: Option<Duration>,
+        start: Option<Instant>,
+        len: usize,
+        mut output: Sink<'_, R>,
+        splitter: &mut dyn Splitter<'a, R>,
+    ) -> Option<SplitStats> {
+        dests.complete(&mut l0);
+        if let (Some(stats), Some(split_time)) = (&mut self.stats, split_time) {
+            stats.record_split(0, len, split_time);
+        }
+        self.notify(Event::BucketSplit {
+            level: 0,
+            bucket_id: 0,
+            len,
+        });
+
+        let mut top_level = Bucket::Split(l0.into());
+        self.split_tree(&mut top_level, 0, 0, &mut output, splitter);
+
+        self.top_level = Some(top_level);
+        // without a budget, this cannot fail
+        self.finish_sort(start).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Find the records that would be at each of `ranks` if `input` were sorted, for `quantile::select_many`.
+    ///
+    /// `ranks` must be sorted, each with the index in `res` to put its record at. `input` is split on its first byte
+    /// into our slices, and from then on only the buckets holding one of `ranks` are split further.
+    pub(crate) fn select(
+        &mut self,
+        input: &[R],
+        ranks: &[(usize, usize)],
+        res: &mut [R],
+        splitter: &mut dyn Splitter<'a, R>,
+    ) {
+        assert!(
+            self.budget.is_none(),
+            "selection does not support a memory budget"
+        );
+        let start = self.start_sort(input.len());
+        self.enter_phase(Phase::L0);
+        let mut dests = ActiveSlices::default();
+        let mut l0 = SplittingBucket::default();
+        splitter.split(input, 0, 56, 0xff, &mut dests, &mut l0, self);
+        dests.complete(&mut l0);
+
+        self.enter_phase(Phase::Deeper);
+        self.select_in(l0, 1, 0, ranks, res, splitter);
+        // without a budget, this cannot fail
+        self.finish_sort(start).unwrap_or_else(|err| panic!("{err}"));
+    }
+
+    /// Select `ranks` from the children of `bucket`, whose keys all share their first `level` bytes, and the first
+    /// of which starts at rank `first_rank`. The slices of every child are freed.
+    fn select_in(
+        &mut self,
+        bucket: SplittingBucket<'a, R>,
+        level: usize,
+        mut first_rank: usize,
+        mut ranks: &[(usize, usize)],
+        res: &mut [R],
+        splitter: &mut dyn Splitter<'a, R>,
     ) {
+        let num_levels = R::KEY_BYTES;
+        let children: [UnsplitBucket<'a, R>; NUM_BUCKETS] = *bucket.children;
+        for mut child in children {
+            let len = child.slices.iter().map(|slice| slice.len()).sum::<usize>();
+            let num_wanted = ranks
+                .iter()
+                .take_while(|&&(rank, _)| rank < first_rank + len)
+                .count();
+            let (wanted, rest) = ranks.split_at(num_wanted);
+            ranks = rest;
+
+            if !wanted.is_empty() && level < num_levels && child.slices.len() > 1 {
+                let word = level / MAX_LEVEL_SPLIT as usize;
+                let shift = (MAX_LEVEL_SPLIT - 1 - (level % MAX_LEVEL_SPLIT as usize) as u8) * 8;
+                let split = child.split(self, splitter, word, shift, 0xFF);
+                self.select_in(split, level + 1, first_rank, wanted, res, splitter);
+            } else {
+                if let [slice] = &mut child.slices[..] {
+                    if !wanted.is_empty() && level < num_levels {
+                        self.enter_phase(Phase::BaseCase);
+                        splitter.sort_in_place(slice);
+                        self.enter_phase(Phase::Deeper);
+                    }
+                }
+                // otherwise, we have split on every byte, so the slices are in order already
+                let mut records = child.slices.iter().flat_map(|slice| slice.iter());
+                let (mut at, mut last) = (first_rank, None);
+                for &(rank, ix) in wanted {
+                    // the same rank may be wanted more than once
+                    if rank >= at {
+                        last = records.nth(rank - at).copied();
+                        at = rank + 1;
+                    }
+                    res[ix] = last.unwrap();
+                }
+                for slice in child.slices.drain(..) {
+                    self.free_slice(slice);
+                }
+            }
+            first_rank += len;
+        }
+    }
+
+    /// Get ready to sort `len` records. Returns the start time, if we collect stats.
+    fn start_sort(&mut self, len: usize) -> Option<Instant> {
+        self.progress = Progress {
+            records_total: len,
+            ..Progress::default()
+        };
+        // every slice in the pool is free between sorts
+        debug_assert!(self.free_slices.is_empty());
+        let pooled = self.pool.slices().iter().map(|&addr| addr as *mut R);
+        self.free_slices.extend(pooled);
+        if let Some(budget) = &mut self.budget {
+            budget.held.store(self.pool.len(), Ordering::Relaxed);
+            budget.exceeded = false;
+        }
+        self.start_stats()
+    }
+
+    fn finish_sort(&mut self, start: Option<Instant>) -> Result<Option<SplitStats>, BudgetExceeded> {
+        self.enter_phase(Phase::Done);
+        self.recycle_slices();
+        let stats = self.finish_stats(start);
+        match &self.budget {
+            Some(budget) if budget.exceeded => Err(BudgetExceeded {
+                budget_bytes: budget.bytes,
+            }),
+            _ => Ok(stats),
+        }
+    }
+
+    /// Reserve the free slices to split `input` on its first byte, within the budget.
+    fn reserve_for_l0(&mut self, input: &[R]) -> bool {
+        // input slices that do not start on a slice boundary are not reused, see `free_slice`
+        let not_reused = input
+            .chunks_exact(slice_len::<R>())
+            .filter(|slice| !slice.as_ptr().is_aligned_to(SLICE_SIZE_BYTES))
+            .count();
+        self.reserve_free_slices(NUM_BUCKETS + 1 + not_reused)
+    }
+
+    /// Handle a bucket that we cannot split within the budget, whose records are already in their place in the
+    /// output.
+    fn sort_over_budget(&mut self, records: &mut [R], splitter: &mut dyn Splitter<'a, R>) {
+        let sort_in_place = self
+            .budget
+            .as_ref()
+            .is_some_and(|budget| budget.over_budget == OverBudget::SortInPlace);
+        if sort_in_place {
+            self.enter_phase(Phase::BaseCase);
+            splitter.sort_in_place(records);
+            if let Some(stats) = &mut self.stats {
+                stats.buckets_sorted_in_place += 1;
+            }
+        }
+        self.notify(Event::RecordsDone(records.len()));
+    }
+
+    /// Split `input` on the first byte of its key.
+    fn split_l0(
+        &mut self,
+        input: &'a mut [R],
+        splitter: &mut dyn Splitter<'a, R>,
+    ) -> SplittingBucket<'a, R> {
+        assert!(size_of::<R>() != 0 && size_of::<R>() <= SLICE_SIZE_BYTES);
+        self.enter_phase(Phase::L0);
         let input_len = input.len();
-        assert!(input_len % SLICE_SIZE == 0);
+        assert!(input_len.is_multiple_of(slice_len::<R>()));
 
-        let slices = input.chunks_exact_mut(SLICE_SIZE);
+        let slices = input.chunks_exact_mut(slice_len::<R>());
 
         let l0 = UnsplitBucket {
             slices: slices.collect(),
         };
         // TODO parametrize splits
         let l0shift = 56;
-        let l0 = l0.split(self, splitter, l0shift, 0xff);
+        self.notify(Event::LevelStart(0));
+        let split_start = self.stats_start();
+        let l0 = l0.split(self, splitter, 0, l0shift, 0xff);
+        self.record_split(0, input_len, split_start);
+        self.notify(Event::BucketSplit {
+            level: 0,
+            bucket_id: 0,
+            len: input_len,
+        });
 
         debug_assert_eq!(
             l0.children
@@ -280,18 +1260,28 @@ impl<'a> Scheduler<'a> {
             input_len
         );
 
-        let mut top_level = Some(Bucket::Split(l0.into()));
-        eprintln!("Finished L0");
+        l0
+    }
+
+    /// Completely sort `root`, a bucket whose keys all share their first `root_level` bytes, into `output`.
+    ///
+    /// `bucket_id` holds those shared bytes, as far as they are in the first word of the key.
+    fn split_tree(
+        &mut self,
+        root: &mut Bucket<'a, R>,
+        root_level: usize,
+        mut bucket_id: u64,
+        output: &mut Sink<'_, R>,
+        splitter: &mut dyn Splitter<'a, R>,
+    ) {
         let mut output_ix = 0;
+        self.enter_phase(Phase::Deeper);
+
+        let num_levels = R::KEY_BYTES;
 
         // TODO replace this with FixedVec?
-        let mut stack = Vec::with_capacity(8);
-        let mut bucket_id: u64 = 0;
-        if let Some(Bucket::Split(SplitBucket { ref mut children })) = top_level {
-            stack.push(children.iter_mut().enumerate())
-        } else {
-            panic!("Destructuring known value failed");
-        };
+        let mut stack = Vec::with_capacity(num_levels);
+        stack.push(std::slice::from_mut(root).iter_mut().enumerate());
 
         while let Some(bucket) = stack.last_mut() {
             let Some((ix, child)) = bucket.next() else {
@@ -299,15 +1289,20 @@ impl<'a> Scheduler<'a> {
                 stack.pop(); continue;
             };
 
-            let level = stack.len();
+            // every key in this child shares its first `level` bytes, so we split it on the next one
+            let level = root_level + stack.len() - 1;
+            let word = level / MAX_LEVEL_SPLIT as usize;
+            let shift = (MAX_LEVEL_SPLIT - 1 - (level % MAX_LEVEL_SPLIT as usize) as u8) * 8;
 
             // we *should* always take this branch, since we only create unsplit buckets and never examine a bucket
             // multiple times
             if let Bucket::Unsplit(ref mut unsplit) = *child {
                 // if we don't need this "{ix}", then we can remove the `.enumerate()` from `stack`
-                let shift = (8 - level as u8) * 8;
-                bucket_id = (bucket_id & !(0xFF << shift)) | ((ix as u64) << shift);
-                // eprint!("\r{bucket_id:#018x}, Splitting L{level} bucket {ix}");
+                // bucket_id only covers the first word of the key
+                if level > root_level && level <= MAX_LEVEL_SPLIT as usize {
+                    let parent_shift = (MAX_LEVEL_SPLIT - level as u8) * 8;
+                    bucket_id = (bucket_id & !(0xFF << parent_shift)) | ((ix as u64) << parent_shift);
+                }
 
                 match unsplit.slices[..] {
                     [] => {
@@ -315,11 +1310,37 @@ impl<'a> Scheduler<'a> {
                         *child = Bucket::Sorted;
                         continue;
                     }
-                    [ref slice] if USE_SMALL_SPLIT => {
-                        // eprint!("; finishing");
-                        splitter
-                            .split_small(slice, &mut output[output_ix..output_ix + slice.len()]);
+                    [_] if USE_SMALL_SPLIT => {
+                        let len = unsplit.slices[0].len();
+                        if let Sink::Slice(output) = output {
+                            self.make_room(output[output_ix..].as_mut_ptr(), len);
+                        }
+                        self.resolve_slices(&mut unsplit.slices);
+                        let slice = &unsplit.slices[0];
+                        self.enter_phase(Phase::BaseCase);
+                        let base_case_start = self.stats_start();
+                        let sorted = match output {
+                            Sink::Slice(output) => &mut output[output_ix..output_ix + len],
+                            // `split_small` overwrites all of it, so it only needs the right length
+                            Sink::Visit { scratch, .. } => {
+                                scratch.clear();
+                                scratch.extend_from_slice(slice);
+                                &mut scratch[..]
+                            }
+                        };
+                        splitter.split_small(slice, sorted);
+                        if let (Some(stats), Some(start)) = (&mut self.stats, base_case_start) {
+                            stats.base_cases += 1;
+                            stats.base_case_time += start.elapsed();
+                        }
+                        self.enter_phase(Phase::Deeper);
+                        output.visit_scratch();
                         output_ix += slice.len();
+                        self.notify(Event::RecordsDone(slice.len()));
+                        // every record is in `output` now, so the slice can go back to the pool
+                        if let Some(slice) = unsplit.slices.pop() {
+                            self.free_slice(slice);
+                        }
                         *child = Bucket::Sorted;
                         continue;
                     }
@@ -332,12 +1353,57 @@ impl<'a> Scheduler<'a> {
                     .map(|slice| slice.len())
                     .sum::<usize>();
 
+                // splitting takes up to a partly filled slice for each child, and one more before the first input
+                // slice is freed
+                if !self.reserve_free_slices(NUM_BUCKETS + 1) {
+                    if let Sink::Slice(output) = output {
+                        self.make_room(output[output_ix..].as_mut_ptr(), unsplit_len);
+                    }
+                    self.resolve_slices(&mut unsplit.slices);
+                    let records = match output {
+                        Sink::Slice(output) => {
+                            let records = &mut output[output_ix..output_ix + unsplit_len];
+                            let mut dest_ix = 0;
+                            for slice in &unsplit.slices {
+                                records[dest_ix..dest_ix + slice.len()].copy_from_slice(slice);
+                                dest_ix += slice.len();
+                            }
+                            records
+                        }
+                        Sink::Visit { scratch, .. } => {
+                            scratch.clear();
+                            for slice in &unsplit.slices {
+                                scratch.extend_from_slice(slice);
+                            }
+                            &mut scratch[..]
+                        }
+                    };
+                    for slice in unsplit.slices.drain(..) {
+                        self.free_slice(slice);
+                    }
+                    self.sort_over_budget(records, splitter);
+                    self.enter_phase(Phase::Deeper);
+                    output.visit_scratch();
+                    output_ix += unsplit_len;
+                    *child = Bucket::Sorted;
+                    continue;
+                }
+
                 // TODO is there a better way to do this and satisfy the borrow checker?
                 // we cannot move `unsplit` out of `*child` since we're matching on a variant, and
                 // that would leave `*child` partially constructed. But we're going to replace it anyway!
                 let mut this_unsplit = UnsplitBucket::default();
                 swap(&mut this_unsplit, unsplit);
-                let this_split = this_unsplit.split(self, splitter, shift, 0xFF);
+                self.resolve_slices(&mut this_unsplit.slices);
+                self.notify(Event::LevelStart(level));
+                let split_start = self.stats_start();
+                let this_split = this_unsplit.split(self, splitter, word, shift, 0xFF);
+                self.record_split(level, unsplit_len, split_start);
+                self.notify(Event::BucketSplit {
+                    level,
+                    bucket_id,
+                    len: unsplit_len,
+                });
 
                 // dbg!((level, ix));
                 debug_assert_eq!(
@@ -355,22 +1421,51 @@ impl<'a> Scheduler<'a> {
 
             // we *should* always take this branch, since we just created a split bucket
             if let Bucket::Split(SplitBucket { ref mut children }) = *child {
-                if level < MAX_LEVEL_SPLIT as usize {
+                if level + 1 < num_levels {
                     stack.push(children.iter_mut().enumerate())
+                } else {
+                    // we have split on every bit, so all keys in a child compare equal (though with a lossy
+                    // KeyTransform, they need not be identical). Their slices are already in order.
+                    let output_ix_before = output_ix;
+                    let len = children
+                        .iter()
+                        .flat_map(|child| match child {
+                            Bucket::Unsplit(UnsplitBucket { slices }) => &slices[..],
+                            _ => &[],
+                        })
+                        .map(|slice| slice.len())
+                        .sum::<usize>();
+                    if let Sink::Slice(output) = output {
+                        self.make_room(output[output_ix..].as_mut_ptr(), len);
+                    }
+                    for child in children.iter_mut() {
+                        if let Bucket::Unsplit(UnsplitBucket { ref mut slices }) = *child {
+                            self.resolve_slices(slices);
+                            for slice in slices.drain(..) {
+                                match output {
+                                    Sink::Slice(output) => {
+                                        output[output_ix..output_ix + slice.len()].copy_from_slice(slice)
+                                    }
+                                    // a visitor can have the slice itself, since it is sorted already
+                                    Sink::Visit { visit, .. } => visit(slice),
+                                }
+                                output_ix += slice.len();
+                                self.free_slice(slice);
+                            }
+                        }
+                        *child = Bucket::Sorted;
+                    }
+                    self.notify(Event::RecordsDone(output_ix - output_ix_before));
                 }
             }
         }
 
-        eprintln!();
-        self.top_level = top_level;
-        self.free_slices.clear();
-
-        for slice in self.allocations.drain(..) {
-            unsafe { dealloc(slice as *mut u8, Self::SLICE_LAYOUT) };
+        if let Sink::Slice(output) = output {
+            debug_assert_eq!(output_ix, output.len());
         }
     }
 
-    pub fn get_splits(&mut self) -> Vec<&mut [u64]> {
+    pub fn get_splits(&mut self) -> Vec<&mut [R]> {
         let mut top_level = None;
         swap(&mut top_level, &mut self.top_level);
 
@@ -395,3 +1490,137 @@ impl<'a> Scheduler<'a> {
         res
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lcg::LCG;
+    use crate::splitters::ScalarSplitter;
+    use crate::transforms::Mask;
+    use crate::verify::{verify_sorted, verify_sorted_by, Checksum, VerifyRuns};
+    use crate::workloads::{test_inputs, Distribution};
+
+    /// Enough slices that skewed inputs are split several levels deep, but few enough to stay fast in debug builds.
+    const LEN: usize = 2 * SLICE_SIZE;
+
+    /// A slice of keys that starts on a slice boundary, as `split_in_place` needs.
+    #[repr(C, align(0x10000))]
+    struct AlignedSlice([u64; SLICE_SIZE]);
+
+    const _: () = assert!(align_of::<AlignedSlice>() == SLICE_SIZE_BYTES);
+
+    fn assert_sorted(dist: &Distribution, keys: &[u64], sorted: &[u64]) {
+        if let Err(err) = verify_sorted(sorted, Checksum::of(keys)) {
+            panic!("{dist:?}: {err}");
+        }
+        let mut expected = keys.to_vec();
+        expected.sort_unstable();
+        assert!(sorted == expected, "{dist:?}: not sorted like sort_unstable");
+    }
+
+    #[test]
+    fn split() {
+        for (dist, keys) in test_inputs(LEN) {
+            let mut input = keys.clone();
+            let mut output = vec![0; LEN];
+            Scheduler::new().split(&mut input, &mut output, &mut ScalarSplitter::new());
+            assert_sorted(&dist, &keys, &output);
+        }
+    }
+
+    #[test]
+    fn split_parallel() {
+        for (dist, keys) in test_inputs(LEN) {
+            let mut input = keys.clone();
+            let mut output = vec![0; LEN];
+            Scheduler::new().split_parallel(&mut input, &mut output, &ScalarSplitter::new(), 3);
+            assert_sorted(&dist, &keys, &output);
+        }
+    }
+
+    #[test]
+    fn max_level_leaves_are_written() {
+        // keys that share their first seven bytes, across more than one slice, so that a bucket is split on the
+        // last byte and its children are leaves of the tree
+        let mut random = LCG::with_seed(5);
+        let keys: Vec<u64> = (0..2 * SLICE_SIZE)
+            .map(|_| 0x0123_4567_89AB_CD00 | (random.next() >> 56))
+            .collect();
+
+        let mut input = keys.clone();
+        let mut output = vec![0; keys.len()];
+        Scheduler::new().split(&mut input, &mut output, &mut ScalarSplitter::new());
+        verify_sorted(&output, Checksum::of(&keys)).unwrap();
+        let mut expected = keys.clone();
+        expected.sort_unstable();
+        assert!(output == expected);
+
+        // with a lossy transform, every key compares equal, and all of them are still written
+        let mut input = keys.clone();
+        let mut output = vec![0; keys.len()];
+        let mask = Mask(!0xFF);
+        Scheduler::new().split(&mut input, &mut output, &mut ScalarSplitter::with_transform(mask));
+        verify_sorted_by(&output, &mask, Checksum::of(&keys)).unwrap();
+    }
+
+    #[test]
+    fn split_in_place() {
+        for (dist, keys) in test_inputs(LEN) {
+            let mut slices: Vec<AlignedSlice> = (0..LEN / SLICE_SIZE)
+                .map(|_| AlignedSlice([0; SLICE_SIZE]))
+                .collect();
+            let records =
+                unsafe { std::slice::from_raw_parts_mut(slices.as_mut_ptr() as *mut u64, LEN) };
+            records.copy_from_slice(&keys);
+            Scheduler::new().split_in_place(records, &mut ScalarSplitter::new());
+            assert_sorted(&dist, &keys, records);
+        }
+    }
+
+    #[test]
+    fn split_visit() {
+        for (dist, keys) in test_inputs(LEN) {
+            let mut input = keys.clone();
+            let mut sorted = vec![];
+            let mut runs = VerifyRuns::new();
+            Scheduler::new().split_visit(&mut input, &mut ScalarSplitter::new(), |run| {
+                sorted.extend_from_slice(run);
+                runs.add(run);
+            });
+            if let Err(err) = runs.finish(Checksum::of(&keys)) {
+                panic!("{dist:?}: {err}");
+            }
+            assert_sorted(&dist, &keys, &sorted);
+        }
+    }
+
+    #[test]
+    fn over_budget_sorts_in_place() {
+        // no slices at all, too few to split the deeper levels, and enough
+        let budgets = [0, (NUM_BUCKETS + 2) * SLICE_SIZE_BYTES, 4 * NUM_BUCKETS * SLICE_SIZE_BYTES];
+        for budget in budgets {
+            for (dist, keys) in test_inputs(LEN) {
+                let mut input = keys.clone();
+                let mut output = vec![0; LEN];
+                let mut sched = Scheduler::new();
+                sched.set_memory_budget(budget, OverBudget::SortInPlace);
+                sched.split(&mut input, &mut output, &mut ScalarSplitter::new());
+                drop(sched);
+                assert_sorted(&dist, &keys, &output);
+            }
+        }
+    }
+
+    #[test]
+    fn over_budget_fails() {
+        let (_, keys) = test_inputs(LEN).swap_remove(0);
+        let mut input = keys.clone();
+        let mut output = vec![0; LEN];
+        let mut sched = Scheduler::new();
+        sched.set_memory_budget(0, OverBudget::Fail);
+        let res = sched.try_split(&mut input, &mut output, &mut ScalarSplitter::new());
+        assert_eq!(res.unwrap_err(), BudgetExceeded { budget_bytes: 0 });
+        drop(sched);
+        assert_eq!(Checksum::of(&output), Checksum::of(&keys));
+    }
+}
@@ -1,44 +1,171 @@
+use std::cmp::Ordering;
+
+use crate::records::Record;
 use crate::scheduler::{ActiveSlices, Scheduler, SplittingBucket};
+use crate::transforms::{Identity, KeyTransform};
 
-pub trait Splitter<'a> {
+pub trait Splitter<'a, R = u64> {
+    #[allow(clippy::too_many_arguments)]
     fn split(
         &mut self,
-        input: &[u64],
+        input: &[R],
+        word: usize,
         shift: u8,
         mask: u64,
-        output: &mut ActiveSlices<'a>,
-        bucket: &mut SplittingBucket<'a>,
-        sched: &mut Scheduler<'a>,
+        output: &mut ActiveSlices<'a, R>,
+        bucket: &mut SplittingBucket<'a, R>,
+        sched: &mut Scheduler<'a, R>,
     );
 
-    fn split_small(&mut self, input: &[u64], output: &mut [u64]);
+    fn split_small(&mut self, input: &[R], output: &mut [R]);
+
+    /// Sort `records` without taking any slices, for buckets that are too large to split within the memory budget.
+    fn sort_in_place(&mut self, records: &mut [R]);
+}
+
+/// Splits records one at a time, ordering them by `transform` applied to each word of their key.
+#[derive(Clone, Default)]
+pub struct ScalarSplitter<T = Identity> {
+    pub transform: T,
+    /// Keep records with equal keys in input order. Splitting is always stable, so this only affects the base case.
+    pub stable: bool,
 }
 
-#[derive(Default)]
-pub struct ScalarSplitter;
+impl ScalarSplitter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<T: KeyTransform> ScalarSplitter<T> {
+    pub fn with_transform(transform: T) -> Self {
+        Self {
+            transform,
+            stable: false,
+        }
+    }
+
+    pub fn stable(self) -> Self {
+        Self {
+            stable: true,
+            ..self
+        }
+    }
+
+    fn cmp_keys<R: Record>(&self, a: &R, b: &R) -> Ordering {
+        (0..R::KEY_WORDS)
+            .map(|word| {
+                let a = self.transform.transform(a.key_word(word));
+                let b = self.transform.transform(b.key_word(word));
+                a.cmp(&b)
+            })
+            .find(|ord| ord.is_ne())
+            .unwrap_or(Ordering::Equal)
+    }
+}
 
-impl<'a> Splitter<'a> for ScalarSplitter {
+impl<'a, R: Record, T: KeyTransform> Splitter<'a, R> for ScalarSplitter<T> {
     fn split(
         &mut self,
-        input: &[u64],
+        input: &[R],
+        word: usize,
         shift: u8,
         mask: u64,
-        output: &mut ActiveSlices<'a>,
-        bucket: &mut SplittingBucket<'a>,
-        sched: &mut Scheduler<'a>,
+        output: &mut ActiveSlices<'a, R>,
+        bucket: &mut SplittingBucket<'a, R>,
+        sched: &mut Scheduler<'a, R>,
     ) {
         let mut num_elems = output.total_lens_of_full_buckets(bucket);
-        for &key in input {
-            let ix = (key >> shift) & mask;
-            output.insert_element(bucket, sched, key, ix as usize);
+        for &record in input {
+            let ix = (self.transform.transform(record.key_word(word)) >> shift) & mask;
+            output.insert_element(bucket, sched, record, ix as usize);
             debug_assert_eq!(output.total_lens_of_full_buckets(bucket), num_elems + 1);
             num_elems += 1;
         }
     }
 
-    fn split_small(&mut self, input: &[u64], output: &mut [u64]) {
+    fn split_small(&mut self, input: &[R], output: &mut [R]) {
         assert_eq!(input.len(), output.len());
         output.copy_from_slice(input);
-        output.sort();
+        self.sort_in_place(output);
+    }
+
+    fn sort_in_place(&mut self, records: &mut [R]) {
+        if self.stable {
+            records.sort_by(|a, b| self.cmp_keys(a, b));
+        } else {
+            records.sort_unstable_by(|a, b| self.cmp_keys(a, b));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lcg::LCG;
+    use crate::records::KeyValue;
+    use crate::scheduler::{slice_len, OverBudget};
+
+    type Row = KeyValue<usize>;
+
+    /// Records whose value is their index in the input. Most share one key, across several slices; the rest have a
+    /// few hundred keys, each in its own L0 bucket, so that they end up in the base case.
+    fn rows() -> Vec<Row> {
+        let mut random = LCG::with_seed(11);
+        (0..4 * slice_len::<Row>())
+            .map(|value| {
+                let r = random.next();
+                let key = if r % 10 < 7 {
+                    0x4242 << 48
+                } else {
+                    ((r >> 8) % 200) * 0x0101_0101_0101_0101
+                };
+                KeyValue { key, value }
+            })
+            .collect()
+    }
+
+    fn assert_stable(sorted: &[Row]) {
+        assert_eq!(sorted.len(), 4 * slice_len::<Row>());
+        for pair in sorted.windows(2) {
+            assert!(
+                (pair[0].key, pair[0].value) < (pair[1].key, pair[1].value),
+                "{:?} before {:?}",
+                pair[0],
+                pair[1]
+            );
+        }
+    }
+
+    #[test]
+    fn stable_scheduler() {
+        let rows = rows();
+        let mut input = rows.clone();
+        let mut output = vec![Row::default(); rows.len()];
+        Scheduler::new().split(&mut input, &mut output, &mut ScalarSplitter::new().stable());
+        assert_stable(&output);
+
+        // with no slices to split with, everything is sorted by `sort_in_place`
+        let mut input = rows.clone();
+        let mut output = vec![Row::default(); rows.len()];
+        let mut sched = Scheduler::new();
+        sched.set_memory_budget(0, OverBudget::SortInPlace);
+        sched.split(&mut input, &mut output, &mut ScalarSplitter::new().stable());
+        drop(sched);
+        assert_stable(&output);
+    }
+
+    #[test]
+    fn stable_base_case() {
+        let rows = rows();
+        let mut output = vec![Row::default(); rows.len()];
+        ScalarSplitter::new()
+            .stable()
+            .split_small(&rows, &mut output);
+        assert_stable(&output);
+
+        let mut records = rows;
+        ScalarSplitter::new().stable().sort_in_place(&mut records);
+        assert_stable(&records);
     }
 }
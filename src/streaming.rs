@@ -0,0 +1,191 @@
+//! Sorting records that arrive in batches, see `StreamingSorter`.
+
+use std::time::{Duration, Instant};
+
+use crate::records::Record;
+use crate::scheduler::{ActiveSlices, Scheduler, Sink, SplitStats, SplittingBucket};
+use crate::splitters::{ScalarSplitter, Splitter};
+
+/// Sorts records that arrive a batch at a time, e.g. from an ingest pipeline.
+///
+/// Each batch is split on the first byte of its keys as soon as it is pushed, into slices of the scheduler's pool,
+/// so the L0 split overlaps with ingest and the batches need not be kept. `finish` splits the deeper levels.
+pub struct StreamingSorter<'a, R = u64, S = ScalarSplitter> {
+    sched: Scheduler<'a, R>,
+    splitter: S,
+    dests: ActiveSlices<'a, R>,
+    l0: SplittingBucket<'a, R>,
+    len: usize,
+    /// the time the L0 split has taken so far, if we collect stats
+    split_time: Option<Duration>,
+    start: Option<Instant>,
+}
+
+impl StreamingSorter<'static> {
+    pub fn new() -> Self {
+        Self::with_scheduler(Scheduler::new(), ScalarSplitter::new())
+    }
+}
+
+impl Default for StreamingSorter<'static> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a, R: Record, S: Splitter<'a, R>> StreamingSorter<'a, R, S> {
+    /// Sort with `sched` (and its allocator, pool, observer and stats) and `splitter`.
+    ///
+    /// Panics if `sched` has a memory budget, since we cannot turn away a batch once it is pushed.
+    pub fn with_scheduler(mut sched: Scheduler<'a, R>, splitter: S) -> Self {
+        let start = sched.start_streaming();
+        Self {
+            split_time: start.map(|_| Duration::ZERO),
+            sched,
+            splitter,
+            dests: ActiveSlices::default(),
+            l0: SplittingBucket::default(),
+            len: 0,
+            start,
+        }
+    }
+
+    /// Split `records` on the first byte of their keys. They are copied, so they can be reused right away.
+    pub fn push(&mut self, records: &[R]) {
+        let time =
+            self.sched
+                .split_streamed(records, &mut self.dests, &mut self.l0, &mut self.splitter);
+        if let (Some(split_time), Some(time)) = (&mut self.split_time, time) {
+            *split_time += time;
+        }
+        self.len += records.len();
+    }
+
+    /// The number of records pushed so far.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Sort every record pushed into `output`, which must have room for exactly `len` records.
+    ///
+    /// Returns the stats of the sort, if they were enabled with `Scheduler::enable_stats`. The L0 time is that of
+    /// the pushes, and the total time runs from `with_scheduler`.
+    pub fn finish_into(mut self, output: &mut [R]) -> Option<SplitStats> {
+        assert_eq!(
+            output.len(),
+            self.len,
+            "the output must have room for every record pushed"
+        );
+        self.sched.finish_streaming(
+            self.l0,
+            self.dests,
+            self.split_time,
+            self.start,
+            self.len,
+            Sink::Slice(output),
+            &mut self.splitter,
+        )
+    }
+
+    /// Sort every record pushed, handing them to `visit` in order a run at a time, without an output buffer.
+    ///
+    /// See `Scheduler::split_visit` for what the runs are, and `finish_into` for the stats.
+    pub fn finish_visit(mut self, mut visit: impl FnMut(&[R])) -> Option<SplitStats> {
+        self.sched.finish_streaming(
+            self.l0,
+            self.dests,
+            self.split_time,
+            self.start,
+            self.len,
+            Sink::visit(&mut visit),
+            &mut self.splitter,
+        )
+    }
+
+    /// Sort every record pushed, into a new `Vec`.
+    pub fn finish(self) -> Vec<R> {
+        let mut output = Vec::with_capacity(self.len);
+        self.finish_visit(|run| output.extend_from_slice(run));
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::SLICE_SIZE;
+    use crate::verify::{verify_sorted, Checksum, VerifyRuns};
+    use crate::workloads::{test_inputs, Distribution};
+
+    /// Not a whole number of slices, unlike what `Scheduler::split` takes.
+    const LEN: usize = 2 * SLICE_SIZE + 1234;
+
+    /// Push `keys` in batches of uneven sizes.
+    fn push_all(keys: &[u64]) -> StreamingSorter<'static> {
+        let mut sorter = StreamingSorter::new();
+        let mut rest = keys;
+        for batch_len in [0, 1, 1000, SLICE_SIZE + 7].into_iter().cycle() {
+            if rest.is_empty() {
+                break;
+            }
+            let (batch, after) = rest.split_at(batch_len.min(rest.len()));
+            sorter.push(batch);
+            rest = after;
+        }
+        assert_eq!(sorter.len(), keys.len());
+        sorter
+    }
+
+    fn assert_sorted(dist: &Distribution, keys: &[u64], sorted: &[u64]) {
+        if let Err(err) = verify_sorted(sorted, Checksum::of(keys)) {
+            panic!("{dist:?}: {err}");
+        }
+        let mut expected = keys.to_vec();
+        expected.sort_unstable();
+        assert!(
+            sorted == expected,
+            "{dist:?}: not sorted like sort_unstable"
+        );
+    }
+
+    #[test]
+    fn finish() {
+        for (dist, keys) in test_inputs(LEN) {
+            assert_sorted(&dist, &keys, &push_all(&keys).finish());
+        }
+    }
+
+    #[test]
+    fn finish_into() {
+        for (dist, keys) in test_inputs(LEN) {
+            let mut output = vec![0; LEN];
+            push_all(&keys).finish_into(&mut output);
+            assert_sorted(&dist, &keys, &output);
+        }
+    }
+
+    #[test]
+    fn finish_visit() {
+        for (dist, keys) in test_inputs(LEN) {
+            let mut sorted = vec![];
+            let mut runs = VerifyRuns::new();
+            push_all(&keys).finish_visit(|run| {
+                sorted.extend_from_slice(run);
+                runs.add(run);
+            });
+            if let Err(err) = runs.finish(Checksum::of(&keys)) {
+                panic!("{dist:?}: {err}");
+            }
+            assert_sorted(&dist, &keys, &sorted);
+        }
+    }
+
+    #[test]
+    fn nothing_pushed() {
+        assert!(StreamingSorter::new().finish().is_empty());
+    }
+}
@@ -0,0 +1,347 @@
+use std::mem::{swap, take};
+
+use crate::scheduler::{
+    ActiveSlices, Bucket, Scheduler, SplitBucket, SplittingBucket, UnsplitBucket, USE_SMALL_SPLIT,
+};
+
+/// Buckets with at most this many strings are finished with multikey quicksort.
+const SMALL_STRINGS: usize = 64;
+/// Partitions with fewer than this many strings are finished with insertion sort.
+const INSERTION_SORT_STRINGS: usize = 16;
+
+/// A string, sorted lexicographically by its bytes.
+pub trait ByteKey: Copy {
+    fn bytes(&self) -> &[u8];
+}
+
+impl ByteKey for &[u8] {
+    #[inline(always)]
+    fn bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+impl ByteKey for &str {
+    #[inline(always)]
+    fn bytes(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Indexed<'s> {
+    bytes: &'s [u8],
+    ix: usize,
+}
+
+impl ByteKey for Indexed<'_> {
+    #[inline(always)]
+    fn bytes(&self) -> &[u8] {
+        self.bytes
+    }
+}
+
+/// Sort `input` lexicographically by bytes, in place. This sort is not stable.
+///
+/// Each level splits on the byte at that depth, with strings that end at this depth written out directly (they
+/// are all equal, and sort before every string that continues).
+pub fn sort_strings<S: ByteKey>(input: &mut [S]) {
+    if input.len() <= SMALL_STRINGS {
+        multikey_quicksort(input, 0);
+        return;
+    }
+
+    let mut sched = Scheduler::new();
+    let mut output_ix = 0;
+
+    // L0 split. Since we write each empty string to the output after reading it, we can sort in place.
+    let mut dests = ActiveSlices::default();
+    let mut l0 = SplittingBucket::default();
+    for ix in 0..input.len() {
+        let string = input[ix];
+        match string.bytes().first() {
+            None => {
+                input[output_ix] = string;
+                output_ix += 1;
+            }
+            Some(&byte) => dests.insert_element(&mut l0, &mut sched, string, byte as usize),
+        }
+    }
+    dests.complete(&mut l0);
+
+    let l0_depth = 1 + shared_prefix_len(&l0, 1);
+    let mut top_level = Bucket::Split(l0.into());
+    let Bucket::Split(SplitBucket { ref mut children }) = top_level else {
+        unreachable!("we just created a split bucket");
+    };
+    // each entry also holds the number of leading bytes shared by every string in those children
+    let mut stack = vec![(children.iter_mut(), l0_depth)];
+
+    while let Some((bucket, depth)) = stack.last_mut() {
+        let depth = *depth;
+        let Some(child) = bucket.next() else {
+            // this bucket has been fully split, so we can remove it
+            stack.pop();
+            continue;
+        };
+
+        let mut children_depth = depth + 1;
+
+        if let Bucket::Unsplit(ref mut unsplit) = *child {
+            let unsplit_len = unsplit
+                .slices
+                .iter()
+                .map(|slice| slice.len())
+                .sum::<usize>();
+
+            if unsplit_len == 0 {
+                *child = Bucket::Sorted;
+                continue;
+            }
+
+            if (USE_SMALL_SPLIT && unsplit.slices.len() == 1) || unsplit_len <= SMALL_STRINGS {
+                let output = &mut input[output_ix..output_ix + unsplit_len];
+                let mut dest_ix = 0;
+                for slice in unsplit.slices.drain(..) {
+                    output[dest_ix..dest_ix + slice.len()].copy_from_slice(slice);
+                    dest_ix += slice.len();
+                    sched.free_slice(slice);
+                }
+                multikey_quicksort(output, depth);
+                output_ix += unsplit_len;
+                *child = Bucket::Sorted;
+                continue;
+            }
+
+            let mut this_unsplit = UnsplitBucket::default();
+            swap(&mut this_unsplit, unsplit);
+            let this_split = split_strings(this_unsplit, depth, &mut sched, input, &mut output_ix);
+            children_depth += shared_prefix_len(&this_split, children_depth);
+            *child = Bucket::Split(this_split.into());
+        }
+
+        if let Bucket::Split(SplitBucket { ref mut children }) = *child {
+            stack.push((children.iter_mut(), children_depth));
+        }
+    }
+
+    debug_assert_eq!(output_ix, input.len());
+    // dropping `sched` gives its slices back
+}
+
+/// Sort `strings` lexicographically by bytes, in place. This sort is not stable.
+pub fn sort_owned_strings(strings: &mut [String]) {
+    let mut order: Vec<Indexed> = strings
+        .iter()
+        .enumerate()
+        .map(|(ix, string)| Indexed {
+            bytes: string.as_bytes(),
+            ix,
+        })
+        .collect();
+    sort_strings(&mut order);
+    let order: Vec<usize> = order.into_iter().map(|indexed| indexed.ix).collect();
+
+    let mut unsorted: Vec<String> = strings.iter_mut().map(take).collect();
+    for (string, ix) in strings.iter_mut().zip(order) {
+        *string = take(&mut unsorted[ix]);
+    }
+}
+
+/// Split the strings in `bucket` on their byte at `depth`, writing out those that end before it.
+fn split_strings<'a, S: ByteKey>(
+    bucket: UnsplitBucket<'a, S>,
+    depth: usize,
+    sched: &mut Scheduler<'a, S>,
+    output: &mut [S],
+    output_ix: &mut usize,
+) -> SplittingBucket<'a, S> {
+    let mut dests = ActiveSlices::default();
+    let mut res = SplittingBucket::default();
+
+    for slice in bucket.slices {
+        for &string in slice.iter() {
+            match string.bytes().get(depth) {
+                None => {
+                    output[*output_ix] = string;
+                    *output_ix += 1;
+                }
+                Some(&byte) => dests.insert_element(&mut res, sched, string, byte as usize),
+            }
+        }
+        sched.free_slice(slice);
+    }
+
+    dests.complete(&mut res);
+    res
+}
+
+/// If all strings in `bucket` went to the same child, how many bytes after `depth` they all share.
+///
+/// Without this, strings with a long common prefix would be split once for every byte of it.
+fn shared_prefix_len<S: ByteKey>(bucket: &SplittingBucket<S>, depth: usize) -> usize {
+    let mut non_empty = bucket
+        .children
+        .iter()
+        .filter(|child| !child.slices.is_empty());
+    match (non_empty.next(), non_empty.next()) {
+        (Some(child), None) => {
+            common_prefix_len(child.slices.iter().flat_map(|slice| slice.iter()), depth)
+        }
+        _ => 0,
+    }
+}
+
+/// How many bytes after `depth` are shared by all of `strings`.
+fn common_prefix_len<'s, S: ByteKey + 's>(
+    mut strings: impl Iterator<Item = &'s S>,
+    depth: usize,
+) -> usize {
+    let Some(first) = strings.next() else {
+        return 0;
+    };
+    let first = &first.bytes()[depth..];
+    let mut len = first.len();
+    for string in strings {
+        let string = &string.bytes()[depth..];
+        len = len.min(string.len());
+        // comparing the whole prefix at once is much faster than finding the first mismatch byte by byte
+        if string[..len] != first[..len] {
+            len = string.iter().zip(first).take_while(|(a, b)| a == b).count();
+        }
+        if len == 0 {
+            break;
+        }
+    }
+    len
+}
+
+/// The byte at `depth`, shifted up by one so that the end of the string sorts first.
+#[inline(always)]
+fn byte_at<S: ByteKey>(string: &S, depth: usize) -> u16 {
+    string.bytes().get(depth).map_or(0, |&byte| byte as u16 + 1)
+}
+
+/// Bentley and Sedgewick's multikey quicksort, for strings that share their first `depth` bytes.
+fn multikey_quicksort<S: ByteKey>(mut strings: &mut [S], mut depth: usize) {
+    loop {
+        if strings.len() < INSERTION_SORT_STRINGS {
+            for ix in 1..strings.len() {
+                let mut jx = ix;
+                while jx > 0 && strings[jx - 1].bytes()[depth..] > strings[jx].bytes()[depth..] {
+                    strings.swap(jx - 1, jx);
+                    jx -= 1;
+                }
+            }
+            return;
+        }
+
+        let len = strings.len();
+        let pivot = median_of_three(
+            byte_at(&strings[0], depth),
+            byte_at(&strings[strings.len() / 2], depth),
+            byte_at(&strings[strings.len() - 1], depth),
+        );
+
+        // three-way partition into [..lt] < pivot, [lt..gt] == pivot, [gt..] > pivot
+        let mut lt = 0;
+        let mut ix = 0;
+        let mut gt = strings.len();
+        while ix < gt {
+            let byte = byte_at(&strings[ix], depth);
+            if byte < pivot {
+                strings.swap(lt, ix);
+                lt += 1;
+                ix += 1;
+            } else if byte > pivot {
+                gt -= 1;
+                strings.swap(ix, gt);
+            } else {
+                ix += 1;
+            }
+        }
+
+        let (less, rest) = take(&mut strings).split_at_mut(lt);
+        let (equal, greater) = rest.split_at_mut(gt - lt);
+        multikey_quicksort(less, depth);
+        multikey_quicksort(greater, depth);
+
+        if pivot == 0 {
+            // these strings all end here, so they are equal
+            return;
+        }
+        // loop instead of recursing, since long common prefixes would otherwise recurse very deeply
+        depth += 1;
+        if equal.len() == len {
+            depth += common_prefix_len(equal.iter(), depth);
+        }
+        strings = equal;
+    }
+}
+
+fn median_of_three(a: u16, b: u16, c: u16) -> u16 {
+    a.max(b).min(a.min(b).max(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lcg::LCG;
+
+    /// Strings with long shared prefixes, duplicates, empty strings, and strings that are prefixes of others.
+    fn test_strings(len: usize) -> Vec<String> {
+        let prefixes = [
+            "",
+            "a",
+            "ab",
+            "common/prefix/",
+            "common/prefix/deeper/",
+            "\u{e9}t\u{e9}",
+        ];
+        let mut random = LCG::with_seed(len as u64);
+        (0..len)
+            .map(|_| {
+                let r = random.next();
+                let mut string = prefixes[r as usize % prefixes.len()].to_string();
+                for ix in 0..(r >> 8) % 8 {
+                    string.push(b"ab\0~"[(r >> (16 + 2 * ix)) as usize % 4] as char);
+                }
+                string
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sort_strs() {
+        for len in [0, 1, SMALL_STRINGS, 20_000] {
+            let strings = test_strings(len);
+            let mut sorted: Vec<&str> = strings.iter().map(|s| &s[..]).collect();
+            let mut expected = sorted.clone();
+            sort_strings(&mut sorted);
+            expected.sort_unstable();
+            assert_eq!(sorted, expected, "{len} strings");
+        }
+    }
+
+    #[test]
+    fn sort_bytes() {
+        let mut random = LCG::with_seed(1);
+        let bytes: Vec<[u8; 3]> = (0..5000)
+            .map(|_| [0, 1, 0xFF].map(|b| b ^ (random.next() % 3) as u8))
+            .collect();
+        let mut sorted: Vec<&[u8]> = bytes.iter().map(|b| &b[..(b[0] % 4) as usize]).collect();
+        let mut expected = sorted.clone();
+        sort_strings(&mut sorted);
+        expected.sort_unstable();
+        assert_eq!(sorted, expected);
+    }
+
+    #[test]
+    fn sort_owned() {
+        let mut sorted = test_strings(20_000);
+        let mut expected = sorted.clone();
+        sort_owned_strings(&mut sorted);
+        expected.sort_unstable();
+        assert_eq!(sorted, expected);
+    }
+}
@@ -0,0 +1,194 @@
+/// An order-preserving map from keys to the unsigned bits that are actually split on.
+///
+/// Keys are sorted by `transform(key)`, but are never modified: the transform is applied on the fly every time a
+/// bucket index is computed, so no separate pass over the data is needed before or after sorting.
+pub trait KeyTransform {
+    fn transform(&self, key: u64) -> u64;
+}
+
+/// Sort by the raw unsigned bits of the key.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Identity;
+
+/// Reverse the order given by the inner transform.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Descending<T = Identity>(pub T);
+
+/// Sort keys as two's complement `i64`s.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FlipSign;
+
+/// Sort keys as IEEE 754 `f64`s, with negative NaNs first and positive NaNs last.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FloatOrder;
+
+/// Sort by only the bits set in the mask, e.g. to ignore flag bits.
+#[derive(Clone, Copy, Debug)]
+pub struct Mask(pub u64);
+
+const SIGN_BIT: u64 = 1 << 63;
+
+impl KeyTransform for Identity {
+    #[inline(always)]
+    fn transform(&self, key: u64) -> u64 {
+        key
+    }
+}
+
+impl<T: KeyTransform> KeyTransform for Descending<T> {
+    #[inline(always)]
+    fn transform(&self, key: u64) -> u64 {
+        !self.0.transform(key)
+    }
+}
+
+impl KeyTransform for FlipSign {
+    #[inline(always)]
+    fn transform(&self, key: u64) -> u64 {
+        key ^ SIGN_BIT
+    }
+}
+
+impl KeyTransform for FloatOrder {
+    #[inline(always)]
+    fn transform(&self, key: u64) -> u64 {
+        if key & SIGN_BIT == 0 {
+            key ^ SIGN_BIT
+        } else {
+            !key
+        }
+    }
+}
+
+impl KeyTransform for Mask {
+    #[inline(always)]
+    fn transform(&self, key: u64) -> u64 {
+        key & self.0
+    }
+}
+
+impl<F: Fn(u64) -> u64> KeyTransform for F {
+    #[inline(always)]
+    fn transform(&self, key: u64) -> u64 {
+        self(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lcg::LCG;
+    use crate::radix_naive::radix_sort_by;
+    use crate::scheduler::{Scheduler, SLICE_SIZE};
+    use crate::splitters::ScalarSplitter;
+    use crate::verify::{verify_sorted_by, Checksum};
+
+    const LEN: usize = 2 * SLICE_SIZE;
+
+    /// `special` followed by random keys, `LEN` in all.
+    fn keys(special: &[u64]) -> Vec<u64> {
+        let mut random = LCG::with_seed(3);
+        let mut keys = special.to_vec();
+        keys.extend((special.len()..LEN).map(|_| random.next()));
+        keys
+    }
+
+    /// `keys` sorted by `transform` with the scheduler and with the naive radix sort, each checked with
+    /// `verify_sorted_by`.
+    fn sort_both<T: KeyTransform + Clone>(keys: &[u64], transform: T) -> [Vec<u64>; 2] {
+        let mut input = keys.to_vec();
+        let mut output = vec![0; keys.len()];
+        Scheduler::new().split(
+            &mut input,
+            &mut output,
+            &mut ScalarSplitter::with_transform(transform.clone()),
+        );
+        let mut naive: Box<[u64]> = keys.into();
+        radix_sort_by(&mut naive, &transform);
+
+        let checksum = Checksum::of(keys);
+        verify_sorted_by(&output, &transform, checksum).unwrap();
+        verify_sorted_by(&naive, &transform, checksum).unwrap();
+        [output, naive.into_vec()]
+    }
+
+    #[test]
+    fn signed() {
+        let special = [i64::MIN, i64::MIN + 1, -1, 0, 1, i64::MAX].map(|key| key as u64);
+        let keys = keys(&special);
+        let mut expected: Vec<i64> = keys.iter().map(|&key| key as i64).collect();
+        expected.sort_unstable();
+        let expected: Vec<u64> = expected.into_iter().map(|key| key as u64).collect();
+        for sorted in sort_both(&keys, FlipSign) {
+            assert!(sorted == expected);
+        }
+    }
+
+    #[test]
+    fn floats() {
+        let special = [
+            f64::NAN.to_bits(),
+            (-f64::NAN).to_bits(),
+            f64::INFINITY.to_bits(),
+            f64::NEG_INFINITY.to_bits(),
+            0.0f64.to_bits(),
+            (-0.0f64).to_bits(),
+            f64::MIN_POSITIVE.to_bits(),
+            1,
+            f64::MAX.to_bits(),
+            f64::MIN.to_bits(),
+            1.5f64.to_bits(),
+            (-1.5f64).to_bits(),
+        ];
+        let keys = keys(&special);
+        let mut expected: Vec<f64> = keys.iter().map(|&key| f64::from_bits(key)).collect();
+        // `total_cmp` also puts negative NaNs first, then -0.0 before 0.0, and positive NaNs last
+        expected.sort_unstable_by(f64::total_cmp);
+        let expected: Vec<u64> = expected.into_iter().map(f64::to_bits).collect();
+        for sorted in sort_both(&keys, FloatOrder) {
+            assert!(sorted == expected);
+        }
+    }
+
+    #[test]
+    fn descending() {
+        let keys = keys(&[0, u64::MAX, 1 << 63]);
+        let mut expected = keys.clone();
+        expected.sort_unstable_by(|a, b| b.cmp(a));
+        for sorted in sort_both(&keys, Descending(Identity)) {
+            assert!(sorted == expected);
+        }
+
+        let mut expected: Vec<i64> = keys.iter().map(|&key| key as i64).collect();
+        expected.sort_unstable_by(|a, b| b.cmp(a));
+        let expected: Vec<u64> = expected.into_iter().map(|key| key as u64).collect();
+        for sorted in sort_both(&keys, Descending(FlipSign)) {
+            assert!(sorted == expected);
+        }
+    }
+
+    #[test]
+    fn lossy_transforms() {
+        // keys that compare equal need not be identical, so only compare what the transforms keep
+        let keys = keys(&[]);
+        let mask = Mask(0xFF00_FF00_0000_00FF);
+        let mut expected: Vec<u64> = keys.iter().map(|&key| mask.transform(key)).collect();
+        expected.sort_unstable();
+        for sorted in sort_both(&keys, mask) {
+            assert!(sorted
+                .iter()
+                .map(|&key| mask.transform(key))
+                .eq(expected.iter().copied()));
+        }
+
+        let low_half = |key: u64| key << 32;
+        let mut expected: Vec<u64> = keys.iter().map(|&key| key << 32).collect();
+        expected.sort_unstable();
+        for sorted in sort_both(&keys, low_half) {
+            assert!(sorted
+                .iter()
+                .map(|&key| key << 32)
+                .eq(expected.iter().copied()));
+        }
+    }
+}
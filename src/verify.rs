@@ -0,0 +1,171 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+use crate::records::Record;
+use crate::transforms::{Identity, KeyTransform};
+use crate::workloads::mix;
+
+/// An order-independent hash of the keys of a multiset of records.
+///
+/// Take it before sorting, and pass it to `verify_sorted` afterwards: a sort that loses, duplicates or corrupts
+/// keys changes it (except with negligible probability), but one that only reorders them does not.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Checksum {
+    pub len: usize,
+    sum: u64,
+    mixed_sum: u64,
+}
+
+impl Checksum {
+    pub fn of<R: Record>(records: &[R]) -> Self {
+        let mut res = Self::default();
+        for record in records {
+            res.add(record);
+        }
+        res
+    }
+
+    pub fn add<R: Record>(&mut self, record: &R) {
+        // hash the whole key, so that records which only differ after the first word are told apart
+        let mut hash = R::KEY_WORDS as u64;
+        for word in 0..R::KEY_WORDS {
+            hash = mix(hash.wrapping_add(record.key_word(word)));
+        }
+        self.len += 1;
+        self.sum = self.sum.wrapping_add(hash);
+        self.mixed_sum = self.mixed_sum.wrapping_add(mix(hash ^ 0x9e3779b97f4a7c15));
+    }
+
+    /// The checksum of the union of both multisets, e.g. for checksums of chunks taken on different threads.
+    pub fn merge(self, other: Self) -> Self {
+        Self {
+            len: self.len + other.len,
+            sum: self.sum.wrapping_add(other.sum),
+            mixed_sum: self.mixed_sum.wrapping_add(other.mixed_sum),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerifyError {
+    /// The record at `index` is ordered before the one at `index - 1`.
+    OutOfOrder { index: usize },
+    /// There are `actual` records, rather than the `expected` number.
+    WrongLength { expected: usize, actual: usize },
+    /// The records are sorted and there are the right number of them, but they are not the ones we started with.
+    ChecksumMismatch,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::OutOfOrder { index } => {
+                write!(f, "record {index} is ordered before record {}", index - 1)
+            }
+            VerifyError::WrongLength { expected, actual } => {
+                write!(f, "saw {actual} records, expected {expected}")
+            }
+            VerifyError::ChecksumMismatch => write!(f, "the sorted keys are not the input keys"),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Check that `records` are sorted by key, and are a permutation of the records `expected` was taken of.
+pub fn verify_sorted<R: Record>(records: &[R], expected: Checksum) -> Result<(), VerifyError> {
+    verify_sorted_by(records, &Identity, expected)
+}
+
+/// Like `verify_sorted`, for records ordered by `transform` applied to each word of their key.
+pub fn verify_sorted_by<R: Record, T: KeyTransform>(
+    records: &[R],
+    transform: &T,
+    expected: Checksum,
+) -> Result<(), VerifyError> {
+    if records.len() != expected.len {
+        return Err(VerifyError::WrongLength {
+            expected: expected.len,
+            actual: records.len(),
+        });
+    }
+
+    let mut checksum = Checksum::default();
+    for (ix, pair) in records.windows(2).enumerate() {
+        if cmp_keys(&pair[0], &pair[1], transform) == Ordering::Greater {
+            return Err(VerifyError::OutOfOrder { index: ix + 1 });
+        }
+        checksum.add(&pair[0]);
+    }
+    if let Some(last) = records.last() {
+        checksum.add(last);
+    }
+
+    if checksum != expected {
+        return Err(VerifyError::ChecksumMismatch);
+    }
+    Ok(())
+}
+
+/// Like `verify_sorted`, for records that are seen a run at a time, such as those of `Scheduler::split_visit`.
+pub struct VerifyRuns<R> {
+    checksum: Checksum,
+    last: Option<R>,
+    out_of_order: Option<usize>,
+}
+
+impl<R: Record> VerifyRuns<R> {
+    pub fn new() -> Self {
+        Self {
+            checksum: Checksum::default(),
+            last: None,
+            out_of_order: None,
+        }
+    }
+
+    /// Check the next run of records, which should follow on from the last.
+    pub fn add(&mut self, records: &[R]) {
+        for record in records {
+            if self.out_of_order.is_none()
+                && self
+                    .last
+                    .as_ref()
+                    .is_some_and(|last| cmp_keys(last, record, &Identity) == Ordering::Greater)
+            {
+                self.out_of_order = Some(self.checksum.len);
+            }
+            self.last = Some(*record);
+            self.checksum.add(record);
+        }
+    }
+
+    /// Check that the records seen are a permutation of the records `expected` was taken of.
+    pub fn finish(self, expected: Checksum) -> Result<(), VerifyError> {
+        match self.out_of_order {
+            Some(index) => Err(VerifyError::OutOfOrder { index }),
+            None if self.checksum.len != expected.len => Err(VerifyError::WrongLength {
+                expected: expected.len,
+                actual: self.checksum.len,
+            }),
+            None if self.checksum != expected => Err(VerifyError::ChecksumMismatch),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<R: Record> Default for VerifyRuns<R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn cmp_keys<R: Record, T: KeyTransform>(a: &R, b: &R, transform: &T) -> Ordering {
+    (0..R::KEY_WORDS)
+        .map(|word| {
+            transform
+                .transform(a.key_word(word))
+                .cmp(&transform.transform(b.key_word(word)))
+        })
+        .find(|ord| ord.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
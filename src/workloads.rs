@@ -0,0 +1,258 @@
+use crate::generators::KeyGenerator;
+
+/// A distribution of benchmark keys.
+///
+/// Every key is a function of its index, the length of the workload, and the value of the random stream at that
+/// index. So a workload can be generated in parallel chunks (see `KeyGenerator::substream`) with the same result.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Distribution {
+    /// Uniformly random over all `u64`s.
+    Uniform,
+    /// Random keys in ascending order.
+    Sorted,
+    /// Random keys in descending order.
+    ReverseSorted,
+    /// Sorted, except that roughly one in `1 / fraction_unsorted` keys is uniformly random instead.
+    NearlySorted { fraction_unsorted: f64 },
+    /// Every key is the same.
+    AllEqual,
+    /// Only `unique` distinct keys, each equally likely.
+    FewUnique { unique: u64 },
+    /// `unique` distinct keys, where the k-th most common one has probability proportional to `k^-exponent`.
+    /// `unique` must be at least 1.
+    Zipf { unique: u64, exponent: f64 },
+    /// Normally distributed around `2^63`, with a standard deviation of `2^std_dev_bits`, which must be below 64.
+    Gaussian { std_dev_bits: u8 },
+    /// Uniformly random below `2^bits`, for `bits` up to 64.
+    NarrowRange { bits: u8 },
+    /// Helman, Bader and JáJá's staggered distribution: the key range is cut into `blocks` ranges, and the workload
+    /// into `blocks` runs. The first half of the runs take the odd ranges, and the second half the even ranges.
+    /// `blocks` must be at least 1.
+    Staggered { blocks: u64 },
+}
+
+impl Distribution {
+    /// Check that the parameters are in range, see the variants.
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            Distribution::Zipf { unique: 0, .. } => {
+                Err("a Zipf distribution needs at least one unique key".to_string())
+            }
+            Distribution::Gaussian { std_dev_bits } if std_dev_bits >= 64 => Err(format!(
+                "a Gaussian distribution needs a standard deviation below 2^64, not 2^{std_dev_bits}"
+            )),
+            Distribution::NarrowRange { bits } if bits > 64 => Err(format!(
+                "a narrow range needs at most 64 bits, not {bits}"
+            )),
+            Distribution::Staggered { blocks: 0 } => {
+                Err("a staggered distribution needs at least one block".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The key at index `ix` of a workload of `len` keys, where `random` is the random stream's value at `ix`.
+    ///
+    /// This may panic if `validate` fails.
+    pub fn key(&self, ix: usize, len: usize, random: u64) -> u64 {
+        match *self {
+            Distribution::Uniform => random,
+            Distribution::Sorted => sorted_key(ix, len, random),
+            Distribution::ReverseSorted => sorted_key(len - 1 - ix, len, random),
+            Distribution::NearlySorted { fraction_unsorted } => {
+                // use different bits of `random` to decide and to pick the key
+                if unit_float(random.rotate_left(32)) < fraction_unsorted {
+                    random
+                } else {
+                    sorted_key(ix, len, random)
+                }
+            }
+            Distribution::AllEqual => u64::MAX / 3,
+            Distribution::FewUnique { unique } => mix(below(random, unique)),
+            Distribution::Zipf { unique, exponent } => mix(zipf_rank(random, unique, exponent)),
+            Distribution::Gaussian { std_dev_bits } => {
+                // Box-Muller, using each half of `random` as a uniform sample
+                let u1 = ((random >> 32) as f64 + 1.0) / (1u64 << 32) as f64;
+                let u2 = (random & 0xFFFF_FFFF) as f64 / (1u64 << 32) as f64;
+                let normal = (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos();
+                // `as` saturates, so the tails are clamped to the key range
+                ((1u64 << 63) as f64 + normal * (1u64 << std_dev_bits) as f64) as u64
+            }
+            Distribution::NarrowRange { bits } => random.checked_shr(64 - bits as u32).unwrap_or(0),
+            Distribution::Staggered { blocks } => {
+                let block = (ix as u128 * blocks as u128 / len as u128) as u64;
+                let range = if block < blocks / 2 {
+                    2 * block + 1
+                } else {
+                    2 * (block - blocks / 2)
+                };
+                let width = u64::MAX / blocks;
+                range * width + below(random, width)
+            }
+        }
+    }
+
+    /// Fill `buf` with this distribution, using `gen` as the random stream.
+    ///
+    /// Panics if `validate` fails.
+    pub fn fill<G: KeyGenerator>(&self, gen: &G, buf: &mut [u64]) {
+        if let Err(err) = self.validate() {
+            panic!("{err}");
+        }
+        let mut gen = gen.clone();
+        let len = buf.len();
+        for (ix, el) in buf.iter_mut().enumerate() {
+            *el = self.key(ix, len, gen.next_key());
+        }
+    }
+}
+
+/// A random key whose position among all keys in the workload is `ix`.
+fn sorted_key(ix: usize, len: usize, random: u64) -> u64 {
+    let width = u64::MAX / len as u64;
+    ix as u64 * width + below(random, width)
+}
+
+/// A value in `0..n`, using the high bits of `random` (the low bits of `LCG` have short periods).
+fn below(random: u64, n: u64) -> u64 {
+    ((random as u128 * n as u128) >> 64) as u64
+}
+
+/// A value in `[0, 1)`.
+fn unit_float(random: u64) -> f64 {
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Approximately sample a rank in `0..unique` from a Zipf distribution, by inverting its continuous CDF.
+fn zipf_rank(random: u64, unique: u64, exponent: f64) -> u64 {
+    let u = unit_float(random);
+    let n = unique as f64;
+    let rank = if (exponent - 1.0).abs() < 1e-9 {
+        n.powf(u)
+    } else {
+        let e = 1.0 - exponent;
+        ((n.powf(e) - 1.0) * u + 1.0).powf(1.0 / e)
+    };
+    (rank as u64).clamp(1, unique) - 1
+}
+
+/// Scatter small integers over the whole key range, so that distinct values do not all share a top-level bucket.
+pub(crate) fn mix(mut x: u64) -> u64 {
+    // the 64-bit finalizer of MurmurHash3
+    x ^= x >> 33;
+    x = x.wrapping_mul(0xff51afd7ed558ccd);
+    x ^= x >> 33;
+    x = x.wrapping_mul(0xc4ceb9fe1a85ec53);
+    x ^= x >> 33;
+    x
+}
+
+/// `len` keys of each of a spread of distributions, for tests: some split evenly, some share long prefixes or
+/// only have a few distinct keys, so that buckets are split several levels deep.
+#[cfg(test)]
+pub(crate) fn test_inputs(len: usize) -> Vec<(Distribution, Vec<u64>)> {
+    use crate::lcg::LCG;
+
+    [
+        Distribution::Uniform,
+        Distribution::Sorted,
+        Distribution::ReverseSorted,
+        Distribution::AllEqual,
+        Distribution::FewUnique { unique: 5 },
+        Distribution::Zipf {
+            unique: 1000,
+            exponent: 1.0,
+        },
+        Distribution::Gaussian { std_dev_bits: 20 },
+        Distribution::NarrowRange { bits: 16 },
+        Distribution::Staggered { blocks: 16 },
+    ]
+    .into_iter()
+    .map(|dist| {
+        let mut keys = vec![0; len];
+        dist.fill(&LCG::with_seed(len as u64), &mut keys);
+        (dist, keys)
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lcg::LCG;
+
+    const LEN: usize = 10_000;
+
+    fn keys(dist: Distribution) -> Vec<u64> {
+        let mut keys = vec![0; LEN];
+        dist.fill(&LCG::with_seed(1), &mut keys);
+        keys
+    }
+
+    #[test]
+    fn validate() {
+        let bad = [
+            Distribution::Zipf {
+                unique: 0,
+                exponent: 1.0,
+            },
+            Distribution::Gaussian { std_dev_bits: 64 },
+            Distribution::NarrowRange { bits: 65 },
+            Distribution::Staggered { blocks: 0 },
+        ];
+        for dist in bad {
+            assert!(dist.validate().is_err(), "{dist:?}");
+        }
+        for (dist, _) in test_inputs(1) {
+            assert_eq!(dist.validate(), Ok(()));
+        }
+        assert_eq!(Distribution::NarrowRange { bits: 64 }.validate(), Ok(()));
+    }
+
+    #[test]
+    fn chunks_match_the_whole() {
+        // generating in chunks from substreams, as pbs-bench does on several threads, gives the same keys
+        const CHUNK: usize = 999;
+        for (dist, keys) in test_inputs(LEN) {
+            let gen = LCG::with_seed(LEN as u64);
+            let chunked: Vec<u64> = (0..LEN)
+                .step_by(CHUNK)
+                .flat_map(|start| {
+                    let mut gen = gen.substream((start / CHUNK) as u64, CHUNK as u64);
+                    (start..(start + CHUNK).min(LEN))
+                        .map(move |ix| dist.key(ix, LEN, gen.next_key()))
+                })
+                .collect();
+            assert!(chunked == keys, "{dist:?}");
+        }
+    }
+
+    #[test]
+    fn shapes() {
+        assert!(keys(Distribution::Sorted).is_sorted());
+        assert!(keys(Distribution::ReverseSorted).iter().rev().is_sorted());
+        assert!(keys(Distribution::AllEqual)
+            .windows(2)
+            .all(|w| w[0] == w[1]));
+
+        let mut few = keys(Distribution::FewUnique { unique: 5 });
+        few.sort_unstable();
+        few.dedup();
+        assert!(few.len() <= 5);
+
+        assert!(keys(Distribution::NarrowRange { bits: 20 })
+            .iter()
+            .all(|&key| key < 1 << 20));
+        assert!(keys(Distribution::NarrowRange { bits: 0 })
+            .iter()
+            .all(|&key| key == 0));
+
+        // the first half of the staggered runs take the odd ranges
+        let staggered = keys(Distribution::Staggered { blocks: 4 });
+        let range = |key: u64| key / (u64::MAX / 4);
+        assert_eq!(range(staggered[0]), 1);
+        assert_eq!(range(staggered[LEN / 4]), 3);
+        assert_eq!(range(staggered[LEN / 2]), 0);
+        assert_eq!(range(staggered[LEN - 1]), 2);
+    }
+}